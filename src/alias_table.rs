@@ -0,0 +1,102 @@
+use rand::Rng;
+
+/// Vose's alias method.
+/// O(n) to build, O(1) to pick an index with probability proportional to its weight.
+pub struct AliasTable {
+    prob: Vec<f64>,
+    alias: Vec<usize>,
+}
+
+impl AliasTable {
+    /// all weights zero is treated as all weights equal.
+    pub fn new(weights: &[usize]) -> AliasTable {
+        let len = weights.len();
+        let total: usize = weights.iter().sum();
+        let mut scaled: Vec<f64> = if total == 0 {
+            vec![1.0; len]
+        } else {
+            weights
+                .iter()
+                .map(|weight| *weight as f64 * len as f64 / total as f64)
+                .collect()
+        };
+
+        let mut prob = vec![1.0; len];
+        let mut alias: Vec<usize> = (0..len).collect();
+        let mut small = Vec::new();
+        let mut large = Vec::new();
+        for (index, p) in scaled.iter().enumerate() {
+            if *p < 1.0 {
+                small.push(index);
+            } else {
+                large.push(index);
+            }
+        }
+        while let (Some(less), Some(more)) = (small.pop(), large.pop()) {
+            prob[less] = scaled[less];
+            alias[less] = more;
+            scaled[more] = scaled[more] + scaled[less] - 1.0;
+            if scaled[more] < 1.0 {
+                small.push(more);
+            } else {
+                large.push(more);
+            }
+        }
+        // the rest is 1.0 (up to rounding errors), already initialized.
+        AliasTable { prob, alias }
+    }
+
+    pub fn len(&self) -> usize {
+        self.prob.len()
+    }
+
+    /// return none if the table is empty.
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<usize> {
+        let len = self.len();
+        if len == 0 {
+            return None;
+        }
+        let index = rng.gen_range(0..len);
+        if rng.gen::<f64>() < self.prob[index] {
+            Some(index)
+        } else {
+            Some(self.alias[index])
+        }
+    }
+}
+
+#[cfg(test)]
+mod alias_table_test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::alias_table::AliasTable;
+
+    fn counts(weights: &[usize], times: usize) -> Vec<usize> {
+        let table = AliasTable::new(weights);
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut counts = vec![0; weights.len()];
+        for _ in 0..times {
+            counts[table.sample(&mut rng).unwrap()] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn weighted() {
+        let counts = counts(&[1, 3, 0, 6], 100000);
+        assert_eq!(counts[2], 0);
+        assert!((9000..11000).contains(&counts[0]));
+        assert!((28000..32000).contains(&counts[1]));
+        assert!((58000..62000).contains(&counts[3]));
+    }
+
+    #[test]
+    fn zero_and_empty() {
+        let counts = counts(&[0, 0], 10000);
+        assert!(counts[0] > 4000 && counts[1] > 4000);
+
+        let table = AliasTable::new(&[]);
+        assert!(table.sample(&mut StdRng::seed_from_u64(7)).is_none());
+    }
+}
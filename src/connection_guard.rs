@@ -0,0 +1,32 @@
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::Node;
+
+/// One active connection to a node, released when dropped.
+/// Guards can be sent to other threads, the node does not need to be borrowed.
+pub struct ConnectionGuard<T: Hash + Eq + Clone> {
+    id: T,
+    conns: Arc<AtomicUsize>,
+}
+
+impl<T: Hash + Eq + Clone> ConnectionGuard<T> {
+    pub(crate) fn new(node: &Node<T>) -> ConnectionGuard<T> {
+        node.conns.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard {
+            id: node.id.clone(),
+            conns: node.conns.clone(),
+        }
+    }
+
+    pub fn get_id(&self) -> &T {
+        &self.id
+    }
+}
+
+impl<T: Hash + Eq + Clone> Drop for ConnectionGuard<T> {
+    fn drop(&mut self) {
+        self.conns.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -1,9 +1,10 @@
 use crate::errors::{DuplicatedKeyError, NotFoundError};
-use crate::Node;
+use crate::{Node, RequestContext};
 use std::collections::hash_map::DefaultHasher;
 use std::collections::{BTreeMap, HashMap};
 use std::hash::{Hash, Hasher};
 
+#[derive(Clone)]
 pub struct ConsistentHashing {
     nodes: BTreeMap<u64, String>,
     user_nodes: HashMap<String, Node<String>>,
@@ -26,10 +27,44 @@ impl ConsistentHashing {
     }
 }
 
+/// A range of key hashes (inclusive on both ends) whose owner changed between two rings.
+/// `None` means the ring had no nodes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MovedRange {
+    start: u64,
+    end: u64,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl MovedRange {
+    pub fn get_start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn get_end(&self) -> u64 {
+        self.end
+    }
+
+    /// owner before the change.
+    pub fn get_from(&self) -> Option<&String> {
+        self.from.as_ref()
+    }
+
+    /// owner after the change.
+    pub fn get_to(&self) -> Option<&String> {
+        self.to.as_ref()
+    }
+
+    pub fn contains(&self, hash: u64) -> bool {
+        self.start <= hash && hash <= self.end
+    }
+}
+
 impl ConsistentHashing {
     fn replicas_of_node(&self, node: &Node<String>) -> usize {
         let count = node.weight * self.replicas;
-        if count <= 0 {
+        if count == 0 {
             1
         } else {
             count
@@ -40,32 +75,151 @@ impl ConsistentHashing {
         self.get_matching_node(request).map(|item| &item.id)
     }
 
-    /// return none if nodes is empty.
+    /// return none if nodes is empty or all nodes are down.
+    /// down nodes are skipped by walking to the next virtual node owned by a live node.
     pub fn get_matching_node(&self, request: &String) -> Option<&Node<String>> {
-        if self.nodes.is_empty() {
-            return None;
+        self.walk(self.hash(request))
+            .filter_map(|id| self.user_nodes.get(id))
+            .find(|node| !node.is_down())
+    }
+
+    /// hashes `ctx.hash_key()`, or the client address without a hash key.
+    /// skips down nodes and nodes not accepted by the context.
+    /// return none without key and client address.
+    pub fn get_matching_node_for(&self, ctx: &dyn RequestContext<String>) -> Option<&Node<String>> {
+        let key = match (ctx.hash_key(), ctx.client_addr()) {
+            (Some(key), _) => key.to_string(),
+            (None, Some(addr)) => addr.to_string(),
+            (None, None) => return None,
+        };
+        self.walk(self.hash(&key))
+            .filter_map(|id| self.user_nodes.get(id))
+            .find(|node| !node.is_down() && ctx.accepts(node))
+    }
+
+    /// next node clockwise on the ring that is live and was not tried yet, for retries.
+    pub fn get_matching_node_excluding(&self, request: &String, tried: &[String]) -> Option<&Node<String>> {
+        self.walk(self.hash(request))
+            .filter_map(|id| self.user_nodes.get(id))
+            .find(|node| !node.is_down() && !tried.contains(&node.id))
+    }
+
+    /// return the first `n` distinct nodes found by walking the ring clockwise from the request,
+    /// skipping down nodes. (preference list for replicas)
+    pub fn get_matching_nodes(&self, request: &String, n: usize) -> Vec<&Node<String>> {
+        let mut result: Vec<&Node<String>> = Vec::with_capacity(n.min(self.user_nodes.len()));
+        if n == 0 {
+            return result;
         }
-        let mut hasher = DefaultHasher::new();
-        request.hash(&mut hasher);
-        let key = hasher.finish();
+        for id in self.walk(self.hash(request)) {
+            if let Some(node) = self.user_nodes.get(id) {
+                if node.is_down() || result.iter().any(|existed| existed.id == node.id) {
+                    continue;
+                }
+                result.push(node);
+                if result.len() >= n {
+                    break;
+                }
+            }
+        }
+        result
+    }
 
-        match self
-            .nodes
+    /// ids of virtual nodes, clockwise from key (inclusive), wrapping around once.
+    fn walk(&self, key: u64) -> impl Iterator<Item = &String> {
+        self.nodes
             .range(key..)
-            .next()
-            .or_else(|| self.nodes.iter().next())
-        {
-            Some((_, id)) => {
-                return self.user_nodes.get(id);
-            }
-            None => {
-                return self
-                    .nodes
-                    .first_key_value()
-                    .map(|(_, id)| self.user_nodes.get(id))
-                    .flatten();
+            .chain(self.nodes.range(..key))
+            .map(|(_, id)| id)
+    }
+
+    /// position of the request on the ring, see `MovedRange`.
+    pub fn key_hash(&self, request: &String) -> u64 {
+        self.hash(request)
+    }
+
+    /// Hash ranges whose owner differs between `self` (before) and `after`.
+    /// Ownership is by ring position, down flags are not taken into account.
+    /// clone the balancer before changing it to get the `before` ring.
+    pub fn diff(&self, after: &ConsistentHashing) -> Vec<MovedRange> {
+        let mut bounds: Vec<u64> = self.nodes.keys().chain(after.nodes.keys()).copied().collect();
+        bounds.sort_unstable();
+        bounds.dedup();
+        if bounds.last() != Some(&u64::MAX) {
+            bounds.push(u64::MAX);
+        }
+
+        let mut result: Vec<MovedRange> = Vec::new();
+        let mut start = 0;
+        for end in bounds {
+            let from = self.owner_of(end);
+            let to = after.owner_of(end);
+            if from != to {
+                match result.last_mut() {
+                    Some(last)
+                        if last.end.wrapping_add(1) == start
+                            && last.from.as_ref() == from
+                            && last.to.as_ref() == to =>
+                    {
+                        last.end = end;
+                    }
+                    _ => result.push(MovedRange {
+                        start,
+                        end,
+                        from: from.cloned(),
+                        to: to.cloned(),
+                    }),
+                }
             }
+            start = end.wrapping_add(1);
         }
+        result
+    }
+
+    /// virtual nodes ordered by their position on the ring.
+    pub fn virtual_nodes(&self) -> impl Iterator<Item = (u64, &String)> {
+        self.nodes.iter().map(|(hash, id)| (*hash, id))
+    }
+
+    /// fraction of the keyspace owned by each node (by ring position, down flags are ignored).
+    /// the fractions sum to 1 unless the ring is empty.
+    pub fn ownership(&self) -> HashMap<&String, f64> {
+        let mut owned: HashMap<&String, u128> =
+            self.user_nodes.keys().map(|id| (id, 0)).collect();
+        let mut prev = match self.nodes.last_key_value() {
+            Some((hash, _)) => *hash,
+            None => return HashMap::new(),
+        };
+        for (hash, id) in &self.nodes {
+            // a virtual node owns (prev, hash], a single virtual node owns the whole ring.
+            let len = match hash.wrapping_sub(prev) {
+                0 => 1u128 << 64,
+                len => len as u128,
+            };
+            *owned.entry(id).or_insert(0) += len;
+            prev = *hash;
+        }
+        owned
+            .into_iter()
+            .map(|(id, len)| (id, len as f64 / (1u128 << 64) as f64))
+            .collect()
+    }
+
+    /// largest owned fraction divided by the mean fraction, 1.0 is a perfect balance.
+    /// return none if nodes is empty.
+    pub fn peak_to_mean_ratio(&self) -> Option<f64> {
+        let ownership = self.ownership();
+        if ownership.is_empty() {
+            return None;
+        }
+        let mean = 1.0 / ownership.len() as f64;
+        let peak = ownership.values().copied().fold(0.0, f64::max);
+        Some(peak / mean)
+    }
+
+    /// owner of the virtual node at or after the hash, ignoring down flags.
+    fn owner_of(&self, hash: u64) -> Option<&String> {
+        self.walk(hash).next()
     }
 
     fn hash(&self, id: &String) -> u64 {
@@ -98,22 +252,41 @@ impl ConsistentHashing {
     pub fn remove_node(&mut self, id: &String) -> Result<(), NotFoundError> {
         match self.get_node(id) {
             Some(node) => {
-                let count = self.replicas_of_node(&node);
+                let count = self.replicas_of_node(node);
                 for i in 0..count {
                     let key = if i == 0 {
-                        self.hash(&id)
+                        self.hash(id)
                     } else {
-                        self.hash(&format!("{}-{}", id.to_string(), i))
+                        self.hash(&format!("{}-{}", id, i))
                     };
                     self.nodes.remove(&key);
                 }
                 self.user_nodes.remove(id);
-                return Ok(());
+                Ok(())
             }
             None => {
-                return Err(NotFoundError);
+                Err(NotFoundError)
             }
-        };
+        }
+    }
+
+    /// down nodes keep their virtual nodes, so keys move back once the node is up again.
+    pub fn set_down(&mut self, id: &String, down: bool) -> Result<(), NotFoundError> {
+        self.user_nodes
+            .get_mut(id)
+            .map(|node| node.down = down)
+            .ok_or(NotFoundError)
+    }
+
+    /// changes the number of virtual nodes of the node, keeping its other settings.
+    pub fn set_weight(&mut self, id: &String, weight: usize) -> Result<(), NotFoundError> {
+        let mut node = self.get_node(id).cloned().ok_or(NotFoundError)?;
+        self.remove_node(id)?;
+        node.weight = weight;
+        node.effective_weight = weight as i32;
+        // removed above, cannot be duplicated.
+        let _ = self.add_node(node);
+        Ok(())
     }
 
     pub fn contains_id(&mut self, id: &String) -> bool {
@@ -132,7 +305,9 @@ impl ConsistentHashing {
 
 #[cfg(test)]
 mod consistent_hashing_test {
-    use crate::Node;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use crate::{Node, Request, RequestContext};
 
     use super::ConsistentHashing;
 
@@ -196,7 +371,7 @@ mod consistent_hashing_test {
             nodes.push(result.id.clone());
         }
 
-        balancer.remove_node(&nodes.first().unwrap()).unwrap();
+        balancer.remove_node(nodes.first().unwrap()).unwrap();
         assert_eq!(2, balancer.get_nodes().len());
 
         let balancer = balancer;
@@ -205,6 +380,191 @@ mod consistent_hashing_test {
         assert_ne!(node.id, nodes.first().unwrap().clone());
     }
 
+    #[test]
+    fn matching_nodes() {
+        let mut balancer = ConsistentHashing::new(
+            vec![
+                Node::new_with_default_weight("1".to_string()),
+                Node::new_with_default_weight("2".to_string()),
+                Node::new_with_default_weight("3".to_string()),
+                Node::new_with_default_weight("4".to_string()),
+            ],
+            10,
+        );
+        let key = "123".to_string();
+        let nodes = balancer.get_matching_nodes(&key, 3);
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(nodes[0].id, balancer.get_matching_node(&key).unwrap().id);
+        assert_ne!(nodes[0].id, nodes[1].id);
+        assert_ne!(nodes[0].id, nodes[2].id);
+        assert_ne!(nodes[1].id, nodes[2].id);
+
+        assert_eq!(balancer.get_matching_nodes(&key, 10).len(), 4);
+        assert!(balancer.get_matching_nodes(&key, 0).is_empty());
+
+        // removing the first node keeps the order of the others.
+        let ids: Vec<String> = nodes.iter().map(|node| node.id.clone()).collect();
+        balancer.remove_node(&ids[0]).unwrap();
+        let nodes = balancer.get_matching_nodes(&key, 2);
+        assert_eq!(nodes[0].id, ids[1]);
+        assert_eq!(nodes[1].id, ids[2]);
+    }
+
+    #[test]
+    fn matching_nodes_skip_down() {
+        let mut balancer = ConsistentHashing::new(
+            vec![
+                Node::new_with_default_weight("1".to_string()),
+                Node::new_with_default_weight("2".to_string()),
+                Node::new_with_default_weight("3".to_string()),
+            ],
+            10,
+        );
+        let key = "123".to_string();
+        let ids: Vec<String> = balancer
+            .get_matching_nodes(&key, 3)
+            .iter()
+            .map(|node| node.id.clone())
+            .collect();
+        balancer.set_down(&ids[1], true).unwrap();
+        let nodes = balancer.get_matching_nodes(&key, 3);
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].id, ids[0]);
+        assert_eq!(nodes[1].id, ids[2]);
+    }
+
+    #[test]
+    fn down() {
+        let mut balancer = ConsistentHashing::new(
+            vec![
+                Node::new_with_default_weight("1".to_string()),
+                Node::new_with_default_weight("2".to_string()),
+                Node::new_with_default_weight("3".to_string()),
+            ],
+            10,
+        );
+        let key = "123".to_string();
+        let ids: Vec<String> = balancer
+            .get_matching_nodes(&key, 3)
+            .iter()
+            .map(|node| node.id.clone())
+            .collect();
+
+        balancer.set_down(&ids[0], true).unwrap();
+        assert!(balancer.get_node(&ids[0]).unwrap().is_down());
+        assert_eq!(balancer.get_matching_node_id(&key).unwrap(), &ids[1]);
+
+        balancer.set_down(&ids[1], true).unwrap();
+        assert_eq!(balancer.get_matching_node_id(&key).unwrap(), &ids[2]);
+
+        balancer.set_down(&ids[2], true).unwrap();
+        assert!(balancer.get_matching_node(&key).is_none());
+
+        balancer.set_down(&ids[0], false).unwrap();
+        assert_eq!(balancer.get_matching_node_id(&key).unwrap(), &ids[0]);
+        assert!(balancer.set_down(&"4".to_string(), true).is_err());
+    }
+
+    fn assert_moved(before: &ConsistentHashing, after: &ConsistentHashing) {
+        let moved = before.diff(after);
+        for i in 0..1000 {
+            let key = i.to_string();
+            let hash = before.key_hash(&key);
+            let from = before.get_matching_node_id(&key);
+            let to = after.get_matching_node_id(&key);
+            match moved.iter().find(|range| range.contains(hash)) {
+                Some(range) => {
+                    assert_ne!(from, to);
+                    assert_eq!(range.get_from(), from);
+                    assert_eq!(range.get_to(), to);
+                }
+                None => assert_eq!(from, to),
+            }
+        }
+    }
+
+    #[test]
+    fn diff() {
+        let mut balancer = ConsistentHashing::new(
+            vec![
+                Node::new_with_default_weight("1".to_string()),
+                Node::new_with_default_weight("2".to_string()),
+                Node::new_with_default_weight("3".to_string()),
+            ],
+            10,
+        );
+        assert!(balancer.diff(&balancer).is_empty());
+
+        let before = balancer.clone();
+        balancer.add_node(Node::new_with_default_weight("4".to_string())).unwrap();
+        let moved = before.diff(&balancer);
+        assert!(!moved.is_empty());
+        assert!(moved
+            .iter()
+            .all(|range| range.get_to() == Some(&"4".to_string())));
+        assert_moved(&before, &balancer);
+
+        let before = balancer.clone();
+        balancer.remove_node(&"2".to_string()).unwrap();
+        let moved = before.diff(&balancer);
+        assert!(moved
+            .iter()
+            .all(|range| range.get_from() == Some(&"2".to_string())));
+        assert_moved(&before, &balancer);
+
+        let before = balancer.clone();
+        balancer.set_weight(&"1".to_string(), 3).unwrap();
+        assert_eq!(balancer.get_node(&"1".to_string()).unwrap().get_weight(), 3);
+        let moved = before.diff(&balancer);
+        assert!(moved
+            .iter()
+            .all(|range| range.get_to() == Some(&"1".to_string())));
+        assert_moved(&before, &balancer);
+    }
+
+    #[test]
+    fn diff_empty() {
+        let empty = ConsistentHashing::new(vec![], 10);
+        let balancer = ConsistentHashing::new(vec![Node::new_with_default_weight("1".to_string())], 10);
+        let moved = empty.diff(&balancer);
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[0].get_start(), 0);
+        assert_eq!(moved[0].get_end(), u64::MAX);
+        assert_eq!(moved[0].get_from(), None);
+        assert_eq!(moved[0].get_to(), Some(&"1".to_string()));
+    }
+
+    #[test]
+    fn ownership() {
+        let empty = ConsistentHashing::new(vec![], 10);
+        assert!(empty.ownership().is_empty());
+        assert!(empty.peak_to_mean_ratio().is_none());
+
+        let single = ConsistentHashing::new(vec![Node::new_with_default_weight("1".to_string())], 1);
+        assert_eq!(single.virtual_nodes().count(), 1);
+        assert_eq!(single.ownership()[&"1".to_string()], 1.0);
+        assert_eq!(single.peak_to_mean_ratio(), Some(1.0));
+
+        let balancer = ConsistentHashing::new(
+            vec![
+                Node::new("1".to_string(), 1),
+                Node::new("2".to_string(), 1),
+                Node::new("3".to_string(), 2),
+            ],
+            100,
+        );
+        let positions: Vec<u64> = balancer.virtual_nodes().map(|(hash, _)| hash).collect();
+        assert_eq!(positions.len(), 400);
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let ownership = balancer.ownership();
+        assert_eq!(ownership.len(), 3);
+        let total: f64 = ownership.values().sum();
+        assert!((total - 1.0).abs() < 1e-9);
+        assert!(ownership[&"3".to_string()] > ownership[&"1".to_string()]);
+        assert!(balancer.peak_to_mean_ratio().unwrap() >= 1.0);
+    }
+
     #[test]
     fn zero_weight_and_replicas() {
         let mut balancer = ConsistentHashing::new(
@@ -229,7 +589,7 @@ mod consistent_hashing_test {
             nodes.push(result.id.clone());
         }
 
-        balancer.remove_node(&nodes.first().unwrap()).unwrap();
+        balancer.remove_node(nodes.first().unwrap()).unwrap();
         assert_eq!(2, balancer.get_nodes().len());
 
         let balancer = balancer;
@@ -237,4 +597,61 @@ mod consistent_hashing_test {
         let node = balancer.get_matching_node(&first_ip.to_string()).unwrap();
         assert_ne!(node.id, nodes.first().unwrap().clone());
     }
+
+    struct Except(String);
+
+    impl RequestContext<String> for Except {
+        fn hash_key(&self) -> Option<&str> {
+            Some("key")
+        }
+
+        fn accepts(&self, node: &Node<String>) -> bool {
+            *node.get_id() != self.0
+        }
+    }
+
+    #[test]
+    fn request_context() {
+        let balancer = ConsistentHashing::new(
+            (1..=5).map(|id| Node::new_with_default_weight(id.to_string())).collect(),
+            10,
+        );
+        let owner = balancer.get_matching_node_id(&"key".to_string()).unwrap();
+        let request = Request::new().with_hash_key("key");
+        assert_eq!(balancer.get_matching_node_for(&request).unwrap().get_id(), owner);
+
+        let addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let by_addr = balancer.get_matching_node_id(&addr.to_string()).unwrap();
+        let request = Request::new().with_client_addr(addr);
+        assert_eq!(balancer.get_matching_node_for(&request).unwrap().get_id(), by_addr);
+        assert!(balancer.get_matching_node_for(&Request::new()).is_none());
+
+        // the next distinct node on the ring.
+        let second = balancer.get_matching_nodes(&"key".to_string(), 2)[1].get_id();
+        let except = Except(owner.clone());
+        assert_eq!(balancer.get_matching_node_for(&except).unwrap().get_id(), second);
+    }
+
+    #[test]
+    fn excluding() {
+        let mut balancer = ConsistentHashing::new(
+            (1..=5).map(|id| Node::new_with_default_weight(id.to_string())).collect(),
+            10,
+        );
+        let key = "key".to_string();
+        let expected: Vec<String> = balancer
+            .get_matching_nodes(&key, 5)
+            .into_iter()
+            .map(|node| node.get_id().clone())
+            .collect();
+        let mut tried = Vec::new();
+        while let Some(node) = balancer.get_matching_node_excluding(&key, &tried) {
+            tried.push(node.get_id().clone());
+        }
+        assert_eq!(tried, expected);
+
+        balancer.set_down(&expected[1], true).unwrap();
+        let node = balancer.get_matching_node_excluding(&key, &expected[..1]).unwrap();
+        assert_eq!(node.get_id(), &expected[2]);
+    }
 }
@@ -7,6 +7,20 @@ pub struct NotFoundError;
 #[derive(Debug)]
 pub struct DuplicatedKeyError;
 
+/// invalid requirement of a label selector.
+#[derive(Debug)]
+pub struct SelectorParseError(pub String);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AcquireError {
+    /// no live node.
+    Unavailable,
+    /// a live node has reached its max_conns.
+    Saturated,
+    /// a live node is out of tokens, none is saturated.
+    RateLimited,
+}
+
 
 impl fmt::Display for NotFoundError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -22,4 +36,24 @@ impl fmt::Display for DuplicatedKeyError {
     }
 }
 
-impl Error for DuplicatedKeyError {}
\ No newline at end of file
+impl Error for DuplicatedKeyError {}
+
+impl fmt::Display for AcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcquireError::Unavailable => write!(f, "No available node"),
+            AcquireError::Saturated => write!(f, "All nodes saturated"),
+            AcquireError::RateLimited => write!(f, "All nodes rate limited"),
+        }
+    }
+}
+
+impl Error for AcquireError {}
+
+impl fmt::Display for SelectorParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid selector requirement: {:?}", self.0)
+    }
+}
+
+impl Error for SelectorParseError {}
\ No newline at end of file
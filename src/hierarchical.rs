@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::errors::{DuplicatedKeyError, NotFoundError};
+use crate::{Balancer, Node, RequestContext};
+
+/// Balances across groups of nodes, then within the chosen group.
+///
+/// Nodes are grouped by the value of a label (nodes without the label are in the group "").
+/// The outer balancer holds one node per group, its id is the label value and its weight
+/// the weight of the group (1 for groups it does not know yet). Each group has its own inner
+/// balancer, built by the factory. A group is down in the outer balancer while it has no live node.
+pub struct Hierarchical<T: Hash + Eq + Clone, O: Balancer<String>, I: Balancer<T>> {
+    label: String,
+    outer: O,
+    factory: Box<dyn Fn(Vec<Node<T>>) -> I>,
+    groups: HashMap<String, I>,
+    /// group of each node.
+    group_of: HashMap<T, String>,
+    overprovisioning_factor: Option<f64>,
+    panic_threshold: Option<f64>,
+}
+
+impl<T: Hash + Eq + Clone, O: Balancer<String>, I: Balancer<T>> Hierarchical<T, O, I> {
+    pub fn new(
+        label: &str,
+        outer: O,
+        factory: impl Fn(Vec<Node<T>>) -> I + 'static,
+        nodes: Vec<Node<T>>,
+    ) -> Hierarchical<T, O, I> {
+        let mut balancer = Hierarchical {
+            label: label.to_string(),
+            outer,
+            factory: Box::new(factory),
+            groups: HashMap::new(),
+            group_of: HashMap::new(),
+            overprovisioning_factor: None,
+            panic_threshold: None,
+        };
+        let groups: Vec<String> = balancer.outer.get_nodes().iter().map(|group| group.id.clone()).collect();
+        for group in groups {
+            balancer.group_mut(&group);
+        }
+        for node in nodes {
+            // ignore same node
+            let _ = balancer.add_node(node);
+        }
+        balancer
+    }
+
+    pub fn get_outer(&self) -> &O {
+        &self.outer
+    }
+
+    pub fn get_group(&self, group: &str) -> Option<&I> {
+        self.groups.get(group)
+    }
+
+    fn group_name(&self, node: &Node<T>) -> String {
+        node.get_label(&self.label).unwrap_or("").to_string()
+    }
+
+    /// creates the group if needed.
+    fn group_mut(&mut self, group: &str) -> &mut I {
+        if !self.groups.contains_key(group) {
+            if !self.outer.contains_id(&group.to_string()) {
+                // checked above, cannot be duplicated.
+                let _ = self.outer.add_node(Node::new_with_default_weight(group.to_string()));
+            }
+            let mut inner = (self.factory)(Vec::new());
+            inner.set_overprovisioning_factor(self.overprovisioning_factor);
+            inner.set_panic_threshold(self.panic_threshold);
+            self.groups.insert(group.to_string(), inner);
+            self.sync(group);
+        }
+        self.groups.get_mut(group).expect("created above")
+    }
+
+    /// down-propagation: the group is down in the outer balancer without live node.
+    fn sync(&mut self, group: &str) {
+        let live = self
+            .groups
+            .get(group)
+            .is_some_and(|inner| inner.get_nodes().iter().any(|node| !node.is_down()));
+        let _ = self.outer.set_down(&group.to_string(), !live);
+    }
+
+    /// id of a node of the first group, in the order of the outer balancer,
+    /// whose inner balancer selects one.
+    fn select(&mut self, ctx: Option<&dyn RequestContext<T>>) -> Option<T> {
+        let mut tried: Vec<String> = Vec::new();
+        loop {
+            let group = self.outer.next_excluding(&tried)?.id.clone();
+            if let Some(inner) = self.groups.get_mut(&group) {
+                let node = match ctx {
+                    Some(ctx) => inner.next_for(ctx),
+                    None => inner.next(),
+                };
+                if let Some(node) = node {
+                    return Some(node.id.clone());
+                }
+            }
+            tried.push(group);
+        }
+    }
+}
+
+impl<T: Hash + Eq + Clone, O: Balancer<String>, I: Balancer<T>> Balancer<T> for Hierarchical<T, O, I> {
+    fn add_node(&mut self, node: Node<T>) -> Result<(), DuplicatedKeyError> {
+        if self.group_of.contains_key(&node.id) {
+            return Err(DuplicatedKeyError);
+        }
+        let group = self.group_name(&node);
+        let id = node.id.clone();
+        self.group_mut(&group).add_node(node)?;
+        self.group_of.insert(id, group.clone());
+        self.sync(&group);
+        Ok(())
+    }
+
+    /// the group stays in the outer balancer (down) when its last node is removed.
+    fn remove_node(&mut self, id: &T) -> Result<(), NotFoundError> {
+        let group = self.group_of.remove(id).ok_or(NotFoundError)?;
+        self.groups.get_mut(&group).ok_or(NotFoundError)?.remove_node(id)?;
+        self.sync(&group);
+        Ok(())
+    }
+
+    fn contains_id(&mut self, id: &T) -> bool {
+        self.group_of.contains_key(id)
+    }
+
+    fn get_node(&self, id: &T) -> Option<&Node<T>> {
+        self.groups.get(self.group_of.get(id)?)?.get_node(id)
+    }
+
+    /// by group, in the order of the outer balancer.
+    fn get_nodes(&self) -> Vec<&Node<T>> {
+        self.outer
+            .get_nodes()
+            .into_iter()
+            .filter_map(|group| self.groups.get(&group.id))
+            .flat_map(|inner| inner.get_nodes())
+            .collect()
+    }
+
+    fn set_down(&mut self, id: &T, down: bool) -> Result<(), NotFoundError> {
+        let group = self.group_of.get(id).ok_or(NotFoundError)?.clone();
+        self.groups.get_mut(&group).ok_or(NotFoundError)?.set_down(id, down)?;
+        self.sync(&group);
+        Ok(())
+    }
+
+    /// for the outer balancer and every group.
+    fn set_overprovisioning_factor(&mut self, factor: Option<f64>) {
+        self.overprovisioning_factor = factor;
+        self.outer.set_overprovisioning_factor(factor);
+        for inner in self.groups.values_mut() {
+            inner.set_overprovisioning_factor(factor);
+        }
+    }
+
+    /// for the outer balancer and every group.
+    fn set_panic_threshold(&mut self, threshold: Option<f64>) {
+        self.panic_threshold = threshold;
+        self.outer.set_panic_threshold(threshold);
+        for inner in self.groups.values_mut() {
+            inner.set_panic_threshold(threshold);
+        }
+    }
+
+    fn next_id(&mut self) -> Option<&T> {
+        self.next().map(|n| &n.id)
+    }
+
+    fn next(&mut self) -> Option<&Node<T>> {
+        let id = self.select(None)?;
+        self.get_node(&id)
+    }
+
+    fn next_for(&mut self, ctx: &dyn RequestContext<T>) -> Option<&Node<T>> {
+        let id = self.select(Some(ctx))?;
+        self.get_node(&id)
+    }
+}
+
+#[cfg(test)]
+mod hierarchical_test {
+    use crate::{Balancer, BalancerEnum, Node};
+    use crate::hierarchical::Hierarchical;
+    use crate::round_robin::RoundRobin;
+    use crate::weighted_round_robin::WeightedRoundRobin;
+
+    fn map_nodes(array: Vec<(i32, &str)>) -> Vec<Node<i32>> {
+        array.into_iter()
+            .map(|(id, region)| Node::new_with_default_weight(id).with_label("region", region))
+            .collect()
+    }
+
+    #[test]
+    fn simple() {
+        let regions = WeightedRoundRobin::new(vec![Node::new("us".to_string(), 2), Node::new("eu".to_string(), 1)]);
+        let nodes = map_nodes(vec![(1, "us"), (2, "us"), (3, "eu")]);
+        let mut balancer = Hierarchical::new("region", regions, RoundRobin::new, nodes);
+        let sequence: Vec<i32> = (0..6).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![1, 3, 2, 1, 3, 2]);
+        assert_eq!(balancer.get_nodes().len(), 3);
+        assert_eq!(balancer.get_group("us").unwrap().get_nodes().len(), 2);
+
+        // a group without live node is down.
+        balancer.set_down(&1, true).unwrap();
+        balancer.set_down(&2, true).unwrap();
+        assert!(balancer.get_outer().get_node(&"us".to_string()).unwrap().is_down());
+        let sequence: Vec<i32> = (0..3).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![3, 3, 3]);
+
+        balancer.set_down(&2, false).unwrap();
+        assert!(!balancer.get_outer().get_node(&"us".to_string()).unwrap().is_down());
+        balancer.remove_node(&3).unwrap();
+        let sequence: Vec<i32> = (0..3).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![2, 2, 2]);
+
+        balancer.remove_node(&2).unwrap();
+        assert!(balancer.next_id().is_none());
+        assert!(balancer.remove_node(&2).is_err());
+    }
+
+    #[test]
+    fn new_groups() {
+        let mut balancer = Hierarchical::new(
+            "region",
+            crate::new(BalancerEnum::RR, vec![]),
+            |nodes| crate::new(BalancerEnum::WRR, nodes),
+            map_nodes(vec![(1, "us"), (2, "eu")]),
+        );
+        balancer.add_node(Node::new_with_default_weight(3)).unwrap();
+        assert!(balancer.add_node(Node::new_with_default_weight(3)).is_err());
+        assert_eq!(balancer.get_outer().get_nodes().len(), 3);
+        let sequence: Vec<i32> = (0..6).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![1, 2, 3, 1, 2, 3]);
+
+        // an inner balancer that selects nothing (saturated) is skipped for this request.
+        balancer.remove_node(&1).unwrap();
+        balancer.add_node(Node::new_with_default_weight(1).with_label("region", "us").with_max_conns(1)).unwrap();
+        let guard = balancer.acquire().unwrap();
+        assert_eq!(*guard.get_id(), 1);
+        let sequence: Vec<i32> = (0..3).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![2, 3, 2]);
+    }
+}
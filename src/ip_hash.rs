@@ -0,0 +1,220 @@
+use std::hash::Hash;
+use std::net::IpAddr;
+
+use crate::errors::{DuplicatedKeyError, NotFoundError};
+use crate::weighted_round_robin::WeightedRoundRobin;
+use crate::{Balancer, Node, RequestContext};
+
+/// rehashes before falling back to round robin, like nginx.
+const MAX_TRIES: usize = 20;
+
+/// Client address hash with the same assignment as nginx `ip_hash` (with weights).
+///
+/// The hash starts at 89 and takes the first three octets of an IPv4 address or the 16 bytes of
+/// an IPv6 address: `hash = (hash * 113 + byte) % 6271`. The node is found by walking
+/// `hash % total weight` over the weights in insertion order, down nodes included. When that node
+/// is down (or saturated, rate limited, not accepted by the request), the address is hashed again
+/// from the current hash. After 20 rehashes, or with less than two nodes, it falls back to smooth
+/// weighted round robin.
+///
+/// Only nodes of the highest priority level are hashed (nginx has no backup with `ip_hash`),
+/// the others are used by the round robin fallback. Requests without client address (`next`,
+/// `next_for` without `client_addr`) go to the round robin fallback, so they are spread over
+/// the nodes instead of all going to one.
+pub struct IpHash<T: Hash + Eq + Clone> {
+    /// nodes in insertion order, and the fallback.
+    nodes: WeightedRoundRobin<T>,
+}
+
+impl<T: Hash + Eq + Clone> IpHash<T> {
+    pub fn new(nodes: Vec<Node<T>>) -> IpHash<T> {
+        IpHash {
+            nodes: WeightedRoundRobin::new(nodes),
+        }
+    }
+
+    pub fn next_for_addr(&mut self, addr: IpAddr) -> Option<&Node<T>> {
+        let id = self.select(Some(addr), None)?;
+        self.nodes.get_node(&id)
+    }
+
+    /// O(n)
+    fn hash(&self, addr: Option<IpAddr>, ctx: Option<&dyn RequestContext<T>>) -> Option<T> {
+        let bytes = match addr {
+            Some(IpAddr::V4(addr)) => addr.octets()[..3].to_vec(),
+            Some(IpAddr::V6(addr)) => addr.octets().to_vec(),
+            None => return None,
+        };
+        let nodes = self.nodes.get_nodes();
+        let priority = nodes.iter().map(|node| node.priority).min()?;
+        let nodes: Vec<&Node<T>> = nodes.into_iter().filter(|node| node.priority == priority).collect();
+        let total_weight: usize = nodes.iter().map(|node| node.weight).sum();
+        if nodes.len() < 2 || total_weight == 0 {
+            return None;
+        }
+        let mut hash: usize = 89;
+        for _ in 0..=MAX_TRIES {
+            for byte in &bytes {
+                hash = (hash * 113 + *byte as usize) % 6271;
+            }
+            let mut w = hash % total_weight;
+            let mut index = 0;
+            while w >= nodes[index].weight {
+                w -= nodes[index].weight;
+                index += 1;
+            }
+            let node = nodes[index];
+            if !node.is_down()
+                && !node.is_saturated()
+                && !node.is_rate_limited()
+                && ctx.is_none_or(|ctx| ctx.accepts(node))
+            {
+                return Some(node.id.clone());
+            }
+        }
+        None
+    }
+
+    fn select(&mut self, addr: Option<IpAddr>, ctx: Option<&dyn RequestContext<T>>) -> Option<T> {
+        if let Some(id) = self.hash(addr, ctx) {
+            self.nodes.get_node(&id)?.take_token();
+            return Some(id);
+        }
+        let node = match ctx {
+            Some(ctx) => self.nodes.next_for(ctx),
+            None => self.nodes.next(),
+        };
+        node.map(|node| node.id.clone())
+    }
+}
+
+impl<T: Hash + Eq + Clone> Balancer<T> for IpHash<T> {
+    fn add_node(&mut self, node: Node<T>) -> Result<(), DuplicatedKeyError> {
+        self.nodes.add_node(node)
+    }
+
+    fn remove_node(&mut self, id: &T) -> Result<(), NotFoundError> {
+        self.nodes.remove_node(id)
+    }
+
+    fn contains_id(&mut self, id: &T) -> bool {
+        self.nodes.contains_id(id)
+    }
+
+    fn get_node(&self, id: &T) -> Option<&Node<T>> {
+        self.nodes.get_node(id)
+    }
+
+    fn get_nodes(&self) -> Vec<&Node<T>> {
+        self.nodes.get_nodes()
+    }
+
+    fn set_down(&mut self, id: &T, down: bool) -> Result<(), NotFoundError> {
+        self.nodes.set_down(id, down)
+    }
+
+    /// for the round robin fallback.
+    fn set_overprovisioning_factor(&mut self, factor: Option<f64>) {
+        self.nodes.set_overprovisioning_factor(factor);
+    }
+
+    /// for the round robin fallback.
+    fn set_panic_threshold(&mut self, threshold: Option<f64>) {
+        self.nodes.set_panic_threshold(threshold);
+    }
+
+    fn next_id(&mut self) -> Option<&T> {
+        self.next().map(|n| &n.id)
+    }
+
+    /// without client address: smooth weighted round robin, see `next_for_addr`.
+    fn next(&mut self) -> Option<&Node<T>> {
+        let id = self.select(None, None)?;
+        self.nodes.get_node(&id)
+    }
+
+    /// hashes `ctx.client_addr()`.
+    fn next_for(&mut self, ctx: &dyn RequestContext<T>) -> Option<&Node<T>> {
+        let id = self.select(ctx.client_addr(), Some(ctx))?;
+        self.nodes.get_node(&id)
+    }
+}
+
+#[cfg(test)]
+mod ip_hash_test {
+    use std::net::IpAddr;
+
+    use crate::{Balancer, Node, Request};
+    use crate::ip_hash::IpHash;
+
+    fn map_nodes(array: Vec<(i32, usize)>) -> Vec<Node<i32>> {
+        array.into_iter()
+            .map(|(id, weight)| Node::new(id, weight))
+            .collect()
+    }
+
+    fn next(balancer: &mut IpHash<i32>, addr: &str) -> i32 {
+        *balancer.next_for_addr(addr.parse().unwrap()).unwrap().get_id()
+    }
+
+    #[test]
+    fn same_as_nginx() {
+        let mut balancer = IpHash::new(map_nodes(vec![(1, 1), (2, 1), (3, 1)]));
+        assert_eq!(next(&mut balancer, "192.168.1.20"), 2);
+        assert_eq!(next(&mut balancer, "192.168.1.254"), 2);
+        assert_eq!(next(&mut balancer, "10.0.0.1"), 1);
+        assert_eq!(next(&mut balancer, "172.16.5.1"), 2);
+        assert_eq!(next(&mut balancer, "8.8.8.8"), 3);
+        assert_eq!(next(&mut balancer, "2001:db8::1"), 3);
+
+        let mut balancer = IpHash::new(map_nodes(vec![(1, 3), (2, 1), (3, 2)]));
+        assert_eq!(next(&mut balancer, "192.168.1.20"), 3);
+        assert_eq!(next(&mut balancer, "10.0.0.1"), 2);
+        assert_eq!(next(&mut balancer, "2001:db8::1"), 1);
+
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let request = Request::new().with_client_addr(addr);
+        assert_eq!(*balancer.next_for(&request).unwrap().get_id(), 2);
+    }
+
+    #[test]
+    fn rehash() {
+        let mut balancer = IpHash::new(map_nodes(vec![(1, 1), (2, 1), (3, 1)]));
+        balancer.set_down(&2, true).unwrap();
+        assert_eq!(next(&mut balancer, "192.168.1.20"), 3);
+        assert_eq!(next(&mut balancer, "172.16.5.1"), 3);
+        // not moved.
+        assert_eq!(next(&mut balancer, "10.0.0.1"), 1);
+
+        balancer.set_down(&2, false).unwrap();
+        assert_eq!(next(&mut balancer, "192.168.1.20"), 2);
+    }
+
+    #[test]
+    fn fallback() {
+        let mut balancer = IpHash::new(vec![
+            Node::new(1, 1),
+            Node::new(2, 1),
+            Node::new(3, 1).with_backup(true),
+        ]);
+        balancer.set_down(&1, true).unwrap();
+        balancer.set_down(&2, true).unwrap();
+        assert_eq!(next(&mut balancer, "192.168.1.20"), 3);
+
+        balancer.remove_node(&1).unwrap();
+        balancer.remove_node(&3).unwrap();
+        assert!(balancer.next_for_addr("192.168.1.20".parse().unwrap()).is_none());
+        balancer.set_down(&2, false).unwrap();
+        assert_eq!(next(&mut balancer, "192.168.1.20"), 2);
+        assert_eq!(*balancer.next_id().unwrap(), 2);
+    }
+
+    #[test]
+    fn without_addr() {
+        let mut balancer = IpHash::new(map_nodes(vec![(1, 1), (2, 2), (3, 1)]));
+        let sequence: Vec<i32> = (0..4).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![2, 1, 3, 2]);
+        let sequence: Vec<i32> = (0..4).map(|_| *balancer.next_for(&Request::new()).unwrap().get_id()).collect();
+        assert_eq!(sequence, vec![2, 1, 3, 2]);
+    }
+}
@@ -0,0 +1,194 @@
+/// Kinetic segment tree over lines `value(time) = intercept + slope * time`.
+///
+/// Keeps the index of the largest active value (lowest index on ties) while `time` moves forward.
+/// Every internal node remembers the earliest time its winner could change, so advancing
+/// only revisits subtrees whose winner actually changed. amortized O(log² n) per step.
+pub struct KineticTree {
+    size: usize,
+    time: i128,
+    lines: Vec<Line>,
+    best: Vec<Option<usize>>,
+    melt: Vec<i128>,
+}
+
+#[derive(Clone, Copy)]
+struct Line {
+    /// current value when inactive.
+    intercept: i128,
+    slope: i128,
+    active: bool,
+}
+
+const NEVER: i128 = i128::MAX;
+
+impl KineticTree {
+    /// (current value, slope, active) for each index.
+    pub fn new(lines: Vec<(i128, i128, bool)>) -> KineticTree {
+        let size = lines.len().next_power_of_two();
+        let mut tree = KineticTree {
+            size,
+            time: 0,
+            lines: lines
+                .into_iter()
+                .map(|(value, slope, active)| Line {
+                    intercept: value,
+                    slope,
+                    active,
+                })
+                .collect(),
+            best: vec![None; 2 * size],
+            melt: vec![NEVER; 2 * size],
+        };
+        for index in 0..tree.lines.len() {
+            if tree.lines[index].active {
+                tree.best[size + index] = Some(index);
+            }
+        }
+        for pos in (1..size).rev() {
+            tree.recompute(pos);
+        }
+        tree
+    }
+
+    pub fn value(&self, index: usize) -> i128 {
+        let line = self.lines[index];
+        if line.active {
+            line.intercept + line.slope * self.time
+        } else {
+            line.intercept
+        }
+    }
+
+    pub fn is_active(&self, index: usize) -> bool {
+        self.lines[index].active
+    }
+
+    /// inactive lines keep their value until they are active again.
+    pub fn set_active(&mut self, index: usize, active: bool) {
+        if self.lines[index].active == active {
+            return;
+        }
+        let value = self.value(index);
+        let line = &mut self.lines[index];
+        line.active = active;
+        line.intercept = if active {
+            value - line.slope * self.time
+        } else {
+            value
+        };
+        self.update(index);
+    }
+
+    pub fn add(&mut self, index: usize, delta: i128) {
+        self.lines[index].intercept += delta;
+        self.update(index);
+    }
+
+    /// moves time forward by one and returns the index of the largest value.
+    pub fn advance(&mut self) -> Option<usize> {
+        self.time += 1;
+        self.refresh(1);
+        self.best[1]
+    }
+
+    fn update(&mut self, index: usize) {
+        let mut pos = self.size + index;
+        self.best[pos] = if self.lines[index].active {
+            Some(index)
+        } else {
+            None
+        };
+        pos /= 2;
+        while pos >= 1 {
+            self.recompute(pos);
+            pos /= 2;
+        }
+    }
+
+    fn refresh(&mut self, pos: usize) {
+        if self.melt[pos] > self.time {
+            return;
+        }
+        self.refresh(2 * pos);
+        self.refresh(2 * pos + 1);
+        self.recompute(pos);
+    }
+
+    /// children must be up to date.
+    fn recompute(&mut self, pos: usize) {
+        let (left, right) = (2 * pos, 2 * pos + 1);
+        let mut melt = self.melt[left].min(self.melt[right]);
+        let best = match (self.best[left], self.best[right]) {
+            (Some(l), Some(r)) => {
+                let (l_value, r_value) = (self.value(l), self.value(r));
+                let (l_slope, r_slope) = (self.lines[l].slope, self.lines[r].slope);
+                if l_value >= r_value {
+                    // right wins once it is strictly larger.
+                    if r_slope > l_slope {
+                        let gap = r_slope - l_slope;
+                        melt = melt.min(self.time + (l_value - r_value) / gap + 1);
+                    }
+                    Some(l)
+                } else {
+                    // left wins again once it is equal.
+                    if l_slope > r_slope {
+                        let gap = l_slope - r_slope;
+                        let diff = r_value - l_value;
+                        melt = melt.min(self.time + (diff + gap - 1) / gap);
+                    }
+                    Some(r)
+                }
+            }
+            (l, r) => l.or(r),
+        };
+        self.best[pos] = best;
+        self.melt[pos] = melt;
+    }
+}
+
+#[cfg(test)]
+mod kinetic_tree_test {
+    use crate::kinetic_tree::KineticTree;
+
+    fn brute_force(lines: &[(i128, i128, bool)], time: i128) -> Option<usize> {
+        let mut result: Option<(usize, i128)> = None;
+        for (index, (value, slope, active)) in lines.iter().enumerate() {
+            if !active {
+                continue;
+            }
+            let value = value + slope * time;
+            if result.is_none_or(|(_, best)| best < value) {
+                result = Some((index, value));
+            }
+        }
+        result.map(|(index, _)| index)
+    }
+
+    #[test]
+    fn max_over_time() {
+        let lines = vec![
+            (0, 1, true),
+            (5, 0, true),
+            (-20, 4, true),
+            (3, 1, false),
+            (-7, 3, true),
+        ];
+        let mut tree = KineticTree::new(lines.clone());
+        for time in 1..50 {
+            assert_eq!(tree.advance(), brute_force(&lines, time));
+        }
+        assert_eq!(tree.value(3), 3);
+        assert!(!tree.is_active(3));
+    }
+
+    #[test]
+    fn empty() {
+        let mut tree = KineticTree::new(vec![]);
+        assert!(tree.advance().is_none());
+        let mut tree = KineticTree::new(vec![(0, 1, false)]);
+        assert!(tree.advance().is_none());
+        tree.set_active(0, true);
+        assert_eq!(tree.advance(), Some(0));
+        assert_eq!(tree.value(0), 1);
+    }
+}
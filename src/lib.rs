@@ -1,18 +1,56 @@
+use std::collections::BTreeMap;
 use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
-use consistent_hashing::ConsistentHashing;
+use rand::rngs::StdRng;
+use rand::RngCore;
+
+pub use connection_guard::ConnectionGuard;
+pub use consistent_hashing::{ConsistentHashing, MovedRange};
+pub use errors::{AcquireError, SelectorParseError};
+pub use hierarchical::Hierarchical;
+pub use random::ThreadRandom;
+pub use rate_limit::{Clock, ManualClock, SystemClock};
+pub use request::{Correlated, Request, RequestContext};
+pub use selector::Selector;
+pub use sticky_sessions::StickySessions;
+pub use traffic_split::TrafficSplit;
+pub use zone_aware::ZoneAware;
 
 use crate::errors::{DuplicatedKeyError, NotFoundError};
+use crate::interleaved_weighted_round_robin::InterleavedWeightedRoundRobin;
+use crate::ip_hash::IpHash;
+use crate::lvs_weighted_round_robin::LvsWeightedRoundRobin;
 use crate::random::Random;
+use crate::rate_limit::TokenBucket;
+use crate::request::{Excluding, Hedge};
 use crate::round_robin::RoundRobin;
+use crate::scalable_weighted_round_robin::ScalableWeightedRoundRobin;
 use crate::weighted_round_robin::WeightedRoundRobin;
 
+mod alias_table;
+mod connection_guard;
 mod consistent_hashing;
 mod errors;
+mod hierarchical;
+mod interleaved_weighted_round_robin;
+mod ip_hash;
+mod kinetic_tree;
+mod lvs_weighted_round_robin;
 mod nodes;
 mod random;
+mod rate_limit;
+mod request;
 mod round_robin;
+mod scalable_weighted_round_robin;
+mod selection;
+mod selector;
+mod sticky_sessions;
+mod traffic_split;
 mod weighted_round_robin;
+mod zone_aware;
 
 pub trait Balancer<T: Hash + Eq + Clone> {
     fn add_node(&mut self, node: Node<T>) -> Result<(), DuplicatedKeyError>;
@@ -23,14 +61,167 @@ pub trait Balancer<T: Hash + Eq + Clone> {
 
     fn set_down(&mut self, id: &T, down: bool) -> Result<(), NotFoundError>;
 
+    /// None (default): all traffic goes to the highest priority level with a live node.
+    /// Some(factor): traffic spills to lower priority levels gradually, as the healthy fraction
+    /// of a level multiplied by the factor drops below 100%. (Envoy uses 1.4)
+    /// a factor that is not a positive number is the same as None.
+    /// ignored by default, for balancers without priority levels.
+    fn set_overprovisioning_factor(&mut self, _factor: Option<f64>) {}
+
+    /// None (default): down nodes are never selected.
+    /// Some(threshold): when all levels together are not healthy enough to take the traffic
+    /// (like Envoy, see `set_overprovisioning_factor`), a level whose healthy fraction is below
+    /// the threshold is in panic and its nodes are selected regardless of their down flag.
+    /// (Envoy uses 0.5)
+    /// ignored by default, for balancers without priority levels.
+    fn set_panic_threshold(&mut self, _threshold: Option<f64>) {}
+
+    /// saturated nodes (see `Node::with_max_conns`) and nodes out of tokens
+    /// (see `Node::with_rate_limit`) are skipped.
+    /// none does not tell why, `acquire()` returns the reason.
     fn next(&mut self) -> Option<&Node<T>>;
     fn next_id(&mut self) -> Option<&T>;
+
+    /// like `next()`, only nodes accepted by the request context are selected.
+    /// by default, calls `next()` up to once per node until a node is accepted: the rejected
+    /// nodes move the balancer state (and take rate limit tokens), override it to filter
+    /// before selecting.
+    fn next_for(&mut self, ctx: &dyn RequestContext<T>) -> Option<&Node<T>> {
+        for _ in 0..self.get_nodes().len() {
+            let id = self.next()?.id.clone();
+            if self.get_node(&id).is_some_and(|node| ctx.accepts(node)) {
+                return self.get_node(&id);
+            }
+        }
+        None
+    }
+
+    /// like `next()`, only nodes matching the label selector are selected.
+    /// the other nodes keep their state (round robin position, current weight).
+    fn next_matching(&mut self, selector: &Selector) -> Option<&Node<T>> {
+        self.next_for(selector)
+    }
+
+    /// like `next()`, skipping the nodes already tried by earlier attempts of the request.
+    /// return none when every live node was tried.
+    fn next_excluding(&mut self, tried: &[T]) -> Option<&Node<T>> {
+        self.next_for(&Excluding(tried))
+    }
+
+    /// up to `k` distinct nodes for a hedged request, the primary first.
+    /// each hedge avoids nodes correlated with the nodes before it when possible.
+    fn next_hedged(&mut self, k: usize, correlated: Option<Correlated<T>>) -> Vec<&Node<T>> {
+        let mut picked: Vec<Node<T>> = Vec::with_capacity(k);
+        while picked.len() < k {
+            let strict = Hedge { picked: &picked, correlated, strict: true };
+            let node = match self.next_for(&strict) {
+                Some(node) => node.clone(),
+                None => {
+                    let any = Hedge { picked: &picked, correlated, strict: false };
+                    match self.next_for(&any) {
+                        Some(node) => node.clone(),
+                        None => break,
+                    }
+                }
+            };
+            picked.push(node);
+        }
+        picked.iter().filter_map(|node| self.get_node(&node.id)).collect()
+    }
+
+    /// selects a node like `next()` and holds a connection to it until the guard is dropped.
+    /// without node, the error is `Saturated` or `RateLimited` if a live node is, else `Unavailable`.
+    fn acquire(&mut self) -> Result<ConnectionGuard<T>, AcquireError> {
+        if let Some(node) = self.next() {
+            return Ok(ConnectionGuard::new(node));
+        }
+        Err(acquire_error(self.get_nodes()))
+    }
+
+    /// like `acquire()` with `next_for`, e.g. to keep the session of `StickySessions`.
+    fn acquire_for(&mut self, ctx: &dyn RequestContext<T>) -> Result<ConnectionGuard<T>, AcquireError> {
+        if let Some(node) = self.next_for(ctx) {
+            return Ok(ConnectionGuard::new(node));
+        }
+        Err(acquire_error(self.get_nodes().into_iter().filter(|node| ctx.accepts(node)).collect()))
+    }
+}
+
+/// why no node was selected.
+fn acquire_error<T: Hash + Eq + Clone>(nodes: Vec<&Node<T>>) -> AcquireError {
+    // down nodes cannot be selected, whatever their connections or tokens.
+    let nodes: Vec<&Node<T>> = nodes.into_iter().filter(|node| !node.is_down()).collect();
+    if nodes.iter().any(|node| node.is_saturated()) {
+        AcquireError::Saturated
+    } else if nodes.iter().any(|node| node.is_rate_limited()) {
+        AcquireError::RateLimited
+    } else {
+        AcquireError::Unavailable
+    }
+}
+
+/// label of `Node::with_zone`.
+pub const ZONE_LABEL: &str = "zone";
+
+/// boxed balancers, e.g. from `new`, can be wrapped like the others.
+impl<T: Hash + Eq + Clone, B: Balancer<T> + ?Sized> Balancer<T> for Box<B> {
+    fn add_node(&mut self, node: Node<T>) -> Result<(), DuplicatedKeyError> {
+        (**self).add_node(node)
+    }
+
+    fn remove_node(&mut self, id: &T) -> Result<(), NotFoundError> {
+        (**self).remove_node(id)
+    }
+
+    fn contains_id(&mut self, id: &T) -> bool {
+        (**self).contains_id(id)
+    }
+
+    fn get_node(&self, id: &T) -> Option<&Node<T>> {
+        (**self).get_node(id)
+    }
+
+    fn get_nodes(&self) -> Vec<&Node<T>> {
+        (**self).get_nodes()
+    }
+
+    fn set_down(&mut self, id: &T, down: bool) -> Result<(), NotFoundError> {
+        (**self).set_down(id, down)
+    }
+
+    fn set_overprovisioning_factor(&mut self, factor: Option<f64>) {
+        (**self).set_overprovisioning_factor(factor)
+    }
+
+    fn set_panic_threshold(&mut self, threshold: Option<f64>) {
+        (**self).set_panic_threshold(threshold)
+    }
+
+    fn next(&mut self) -> Option<&Node<T>> {
+        (**self).next()
+    }
+
+    fn next_id(&mut self) -> Option<&T> {
+        (**self).next_id()
+    }
+
+    fn next_for(&mut self, ctx: &dyn RequestContext<T>) -> Option<&Node<T>> {
+        (**self).next_for(ctx)
+    }
 }
 
 pub struct Node<T: Hash + Eq + Clone> {
     id: T,
     weight: usize,
     down: bool,
+    priority: usize,
+    /// 0 is no limit.
+    max_conns: usize,
+    /// shared with the connection guards and clones.
+    conns: Arc<AtomicUsize>,
+    /// shared with clones.
+    rate_limit: Option<RateLimit>,
+    labels: BTreeMap<String, String>,
     current_weight: i32,
     effective_weight: i32,
 }
@@ -39,10 +230,15 @@ impl<T: Hash + Eq + Clone> Clone for Node<T> {
     fn clone(&self) -> Self {
         Self {
             id: self.id.clone(),
-            weight: self.weight.clone(),
-            down: self.down.clone(),
-            current_weight: self.current_weight.clone(),
-            effective_weight: self.effective_weight.clone(),
+            weight: self.weight,
+            down: self.down,
+            priority: self.priority,
+            max_conns: self.max_conns,
+            conns: self.conns.clone(),
+            rate_limit: self.rate_limit.clone(),
+            labels: self.labels.clone(),
+            current_weight: self.current_weight,
+            effective_weight: self.effective_weight,
         }
     }
 }
@@ -53,6 +249,11 @@ impl<T: Hash + Eq + Clone> Node<T> {
             id,
             weight: 1,
             down: false,
+            priority: 0,
+            max_conns: 0,
+            conns: Arc::new(AtomicUsize::new(0)),
+            rate_limit: None,
+            labels: BTreeMap::new(),
             current_weight: 0,
             effective_weight: 1,
         }
@@ -63,6 +264,11 @@ impl<T: Hash + Eq + Clone> Node<T> {
             id,
             weight,
             down: false,
+            priority: 0,
+            max_conns: 0,
+            conns: Arc::new(AtomicUsize::new(0)),
+            rate_limit: None,
+            labels: BTreeMap::new(),
             current_weight: 0,
             effective_weight: weight as i32,
         }
@@ -79,6 +285,109 @@ impl<T: Hash + Eq + Clone> Node<T> {
     pub fn is_down(&self) -> bool {
         self.down
     }
+
+    /// backup nodes only receive traffic when all primary nodes are down. (like nginx `backup`)
+    /// same as priority 1 (backup) or 0 (primary).
+    pub fn with_backup(self, backup: bool) -> Node<T> {
+        self.with_priority(usize::from(backup))
+    }
+
+    pub fn is_backup(&self) -> bool {
+        self.priority > 0
+    }
+
+    /// 0 is the highest priority, see `Balancer::set_overprovisioning_factor`.
+    pub fn with_priority(mut self, priority: usize) -> Node<T> {
+        self.priority = priority;
+        self
+    }
+
+    pub fn get_priority(&self) -> usize {
+        self.priority
+    }
+
+    /// maximum number of active connections, 0 is no limit. (like nginx `max_conns`)
+    /// connections are counted by the guards of `Balancer::acquire`.
+    pub fn with_max_conns(mut self, max_conns: usize) -> Node<T> {
+        self.max_conns = max_conns;
+        self
+    }
+
+    pub fn get_max_conns(&self) -> usize {
+        self.max_conns
+    }
+
+    /// number of active connections.
+    pub fn get_conns(&self) -> usize {
+        self.conns.load(Ordering::SeqCst)
+    }
+
+    pub fn is_saturated(&self) -> bool {
+        self.max_conns > 0 && self.get_conns() >= self.max_conns
+    }
+
+    /// token bucket: `rate` requests per second, up to `burst` at once.
+    /// each selection takes a token, nodes without a token are skipped.
+    /// panics if `rate` is negative, infinite or NaN.
+    pub fn with_rate_limit(self, rate: f64, burst: usize) -> Node<T> {
+        self.with_rate_limit_clock(rate, burst, Arc::new(SystemClock))
+    }
+
+    pub fn with_rate_limit_clock(mut self, rate: f64, burst: usize, clock: Arc<dyn Clock>) -> Node<T> {
+        let bucket = TokenBucket::new(rate, burst, clock.now());
+        self.rate_limit = Some(RateLimit {
+            bucket: Arc::new(Mutex::new(bucket)),
+            clock,
+        });
+        self
+    }
+
+    pub fn has_rate_limit(&self) -> bool {
+        self.rate_limit.is_some()
+    }
+
+    /// no token left right now.
+    pub fn is_rate_limited(&self) -> bool {
+        self.rate_limit
+            .as_ref()
+            .is_some_and(|limit| !limit.bucket.lock().unwrap().has_token(limit.clock.now()))
+    }
+
+    /// false if rate limited.
+    pub(crate) fn take_token(&self) -> bool {
+        self.rate_limit
+            .as_ref()
+            .is_none_or(|limit| limit.bucket.lock().unwrap().take(limit.clock.now()))
+    }
+
+    /// metadata of the node (zone, region, version, canary ...), kept by the balancers.
+    pub fn with_label(mut self, key: impl Into<String>, value: impl Into<String>) -> Node<T> {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn get_label(&self, key: &str) -> Option<&str> {
+        self.labels.get(key).map(String::as_str)
+    }
+
+    pub fn get_labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
+
+    /// label `zone`.
+    pub fn with_zone(self, zone: impl Into<String>) -> Node<T> {
+        self.with_label(ZONE_LABEL, zone)
+    }
+
+    pub fn get_zone(&self) -> Option<&str> {
+        self.get_label(ZONE_LABEL)
+    }
+}
+
+#[derive(Clone)]
+struct RateLimit {
+    bucket: Arc<Mutex<TokenBucket>>,
+    clock: Arc<dyn Clock>,
 }
 
 pub enum BalancerEnum {
@@ -86,8 +395,16 @@ pub enum BalancerEnum {
     RR,
     /// Smooth Weighted Round-Robin
     WRR,
-    /// Random
+    /// Smooth Weighted Round-Robin, same sequence as WRR, faster for thousands of nodes.
+    ScalableWRR,
+    /// Weighted Round-Robin of LVS/IPVS
+    LvsWRR,
+    /// Interleaved Weighted Round-Robin
+    IWRR,
+    /// Weighted Random
     Random,
+    /// Client address hash of nginx `ip_hash`, see `Balancer::next_for`.
+    IpHash,
 }
 
 pub fn new<'a, T: Hash + Eq + Clone + 'a>(
@@ -97,7 +414,11 @@ pub fn new<'a, T: Hash + Eq + Clone + 'a>(
     match balancer_enum {
         BalancerEnum::RR => Box::new(RoundRobin::new(nodes)),
         BalancerEnum::WRR => Box::new(WeightedRoundRobin::new(nodes)),
+        BalancerEnum::ScalableWRR => Box::new(ScalableWeightedRoundRobin::new(nodes)),
+        BalancerEnum::LvsWRR => Box::new(LvsWeightedRoundRobin::new(nodes)),
+        BalancerEnum::IWRR => Box::new(InterleavedWeightedRoundRobin::new(nodes)),
         BalancerEnum::Random => Box::new(Random::new(nodes)),
+        BalancerEnum::IpHash => Box::new(IpHash::new(nodes)),
     }
 }
 
@@ -105,6 +426,24 @@ pub fn weighted_round_robin<T: Hash + Eq + Clone>(nodes: Vec<Node<T>>) -> Weight
     WeightedRoundRobin::new(nodes)
 }
 
+/// same sequence as weighted_round_robin, next() is O(log² n) amortized instead of O(n).
+pub fn scalable_weighted_round_robin<T: Hash + Eq + Clone>(
+    nodes: Vec<Node<T>>,
+) -> ScalableWeightedRoundRobin<T> {
+    ScalableWeightedRoundRobin::new(nodes)
+}
+
+/// weighted round robin with the same sequence as LVS/IPVS `wrr`.
+pub fn lvs_weighted_round_robin<T: Hash + Eq + Clone>(nodes: Vec<Node<T>>) -> LvsWeightedRoundRobin<T> {
+    LvsWeightedRoundRobin::new(nodes)
+}
+
+pub fn interleaved_weighted_round_robin<T: Hash + Eq + Clone>(
+    nodes: Vec<Node<T>>,
+) -> InterleavedWeightedRoundRobin<T> {
+    InterleavedWeightedRoundRobin::new(nodes)
+}
+
 pub fn round_robin<T: Hash + Eq + Clone>(nodes: Vec<Node<T>>) -> RoundRobin<T> {
     RoundRobin::new(nodes)
 }
@@ -113,9 +452,144 @@ pub fn random<T: Hash + Eq + Clone>(nodes: Vec<Node<T>>) -> Random<T> {
     Random::new(nodes)
 }
 
+/// Random with a seeded rng, the same seed gives the same sequence.
+pub fn random_with_seed<T: Hash + Eq + Clone>(nodes: Vec<Node<T>>, seed: u64) -> Random<T, StdRng> {
+    Random::with_seed(nodes, seed)
+}
+
+/// Random with any rng, e.g. a mock or a fast non cryptographic rng.
+///
+/// ```
+/// use rand::rngs::StdRng;
+/// use rand::SeedableRng;
+/// use rsbalancer::{Balancer, Node};
+///
+/// let nodes = vec![Node::new(1, 1), Node::new(2, 3)];
+/// let mut first = rsbalancer::random_with_rng(nodes.clone(), StdRng::seed_from_u64(7));
+/// let mut second = rsbalancer::random_with_rng(nodes, StdRng::seed_from_u64(7));
+/// for _ in 0..10 {
+///     assert_eq!(first.next_id(), second.next_id());
+/// }
+/// ```
+pub fn random_with_rng<T: Hash + Eq + Clone, R: RngCore>(nodes: Vec<Node<T>>, rng: R) -> Random<T, R> {
+    Random::with_rng(nodes, rng)
+}
+
+/// same client to node assignment as nginx `ip_hash`, see `IpHash::next_for_addr`.
+pub fn ip_hash<T: Hash + Eq + Clone>(nodes: Vec<Node<T>>) -> IpHash<T> {
+    IpHash::new(nodes)
+}
+
+/// prefers the nodes of the local zone (label `zone`), see `ZoneAware`.
+pub fn zone_aware<T: Hash + Eq + Clone, B: Balancer<T>>(inner: B, local_zone: &str) -> ZoneAware<T, B> {
+    ZoneAware::new(inner, local_zone)
+}
+
+/// balances across the groups of nodes with the same `label` value with `outer`,
+/// then within the group with the balancer built by `factory`, see `Hierarchical`.
+pub fn hierarchical<T: Hash + Eq + Clone, O: Balancer<String>, I: Balancer<T>>(
+    label: &str,
+    outer: O,
+    factory: impl Fn(Vec<Node<T>>) -> I + 'static,
+    nodes: Vec<Node<T>>,
+) -> Hierarchical<T, O, I> {
+    Hierarchical::new(label, outer, factory, nodes)
+}
+
+/// sends a percentage of the requests to the nodes matching the canary selector,
+/// see `TrafficSplit`.
+pub fn traffic_split<'a, T: Hash + Eq + Clone + 'a>(
+    stable: BalancerEnum,
+    canary: BalancerEnum,
+    selector: Selector,
+    nodes: Vec<Node<T>>,
+) -> TrafficSplit<'a, T> {
+    TrafficSplit::new(stable, canary, selector, nodes)
+}
+
+/// remembers the node of each session for `ttl`, at most `capacity` sessions,
+/// see `StickySessions`.
+pub fn sticky_sessions<T: Hash + Eq + Clone, B: Balancer<T>>(
+    inner: B,
+    ttl: Duration,
+    capacity: usize,
+) -> StickySessions<T, B> {
+    StickySessions::new(inner, ttl, capacity)
+}
+
 /// ConsistentHashing
 /// number of virtual nodes: replicas * node.weight.
-/// node.down does not work in ConsistentHash now.(use removeNode() instead)
+/// down nodes are skipped: their keys go to the next live node on the ring.
 pub fn consistent_hashing(nodes: Vec<Node<String>>, replicas: usize) -> ConsistentHashing {
     ConsistentHashing::new(nodes, replicas)
 }
+
+#[cfg(test)]
+mod lib_test {
+    use crate::errors::{DuplicatedKeyError, NotFoundError};
+    use crate::{Balancer, Node, Selector};
+
+    /// implements only the required methods.
+    struct Cycle {
+        nodes: Vec<Node<i32>>,
+        index: usize,
+    }
+
+    impl Balancer<i32> for Cycle {
+        fn add_node(&mut self, node: Node<i32>) -> Result<(), DuplicatedKeyError> {
+            self.nodes.push(node);
+            Ok(())
+        }
+
+        fn remove_node(&mut self, id: &i32) -> Result<(), NotFoundError> {
+            self.nodes.retain(|node| node.id != *id);
+            Ok(())
+        }
+
+        fn contains_id(&mut self, id: &i32) -> bool {
+            self.get_node(id).is_some()
+        }
+
+        fn get_node(&self, id: &i32) -> Option<&Node<i32>> {
+            self.nodes.iter().find(|node| node.id == *id)
+        }
+
+        fn get_nodes(&self) -> Vec<&Node<i32>> {
+            self.nodes.iter().collect()
+        }
+
+        fn set_down(&mut self, _id: &i32, _down: bool) -> Result<(), NotFoundError> {
+            Err(NotFoundError)
+        }
+
+        fn next(&mut self) -> Option<&Node<i32>> {
+            if self.nodes.is_empty() {
+                return None;
+            }
+            self.index = (self.index + 1) % self.nodes.len();
+            self.nodes.get(self.index)
+        }
+
+        fn next_id(&mut self) -> Option<&i32> {
+            self.next().map(|n| &n.id)
+        }
+    }
+
+    #[test]
+    fn default_methods() {
+        let nodes = vec![
+            Node::new_with_default_weight(1).with_zone("a"),
+            Node::new_with_default_weight(2).with_zone("b"),
+            Node::new_with_default_weight(3).with_zone("a"),
+        ];
+        let mut balancer = Cycle { nodes, index: 0 };
+        balancer.set_overprovisioning_factor(Some(1.4));
+        balancer.set_panic_threshold(Some(0.5));
+
+        let selector = Selector::parse("zone=b").unwrap();
+        assert_eq!(*balancer.next_matching(&selector).unwrap().get_id(), 2);
+        assert_eq!(*balancer.next_matching(&selector).unwrap().get_id(), 2);
+        assert!(balancer.next_matching(&Selector::parse("zone=c").unwrap()).is_none());
+        assert_eq!(*balancer.next_excluding(&[1, 3]).unwrap().get_id(), 2);
+    }
+}
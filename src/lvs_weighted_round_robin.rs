@@ -0,0 +1,298 @@
+use std::collections::BTreeMap;
+use std::hash::Hash;
+
+use crate::{Balancer, Node, RequestContext};
+use crate::errors::{DuplicatedKeyError, NotFoundError};
+use crate::nodes::NodesContainer;
+use crate::selection::{self, Filter, Priorities, Select};
+
+/// Weighted round robin of LVS/IPVS (`ip_vs_wrr.c`).
+/// Each pass over the nodes picks the ones with weight >= current weight,
+/// the current weight goes from the max weight down to the gcd of weights.
+pub struct LvsWeightedRoundRobin<T: Hash + Eq + Clone> {
+    nodes: NodesContainer<T>,
+    priorities: Priorities,
+    /// by priority, updated when nodes change.
+    levels: BTreeMap<usize, LevelState>,
+}
+
+/// passes over the nodes of a priority level.
+#[derive(Default)]
+struct LevelState {
+    max_weight: usize,
+    gcd_weight: usize,
+    cursor: Cursor,
+    /// of the selections with a request filter (`next_for`, `next_matching`...),
+    /// they do not move the position of `next()`.
+    filtered: Cursor,
+}
+
+/// position in the passes.
+#[derive(Clone, Copy, Default)]
+struct Cursor {
+    /// none is before the first node.
+    index: Option<usize>,
+    current_weight: usize,
+}
+
+impl<T: Hash + Eq + Clone> LvsWeightedRoundRobin<T> {
+    pub fn new(nodes: Vec<Node<T>>) -> LvsWeightedRoundRobin<T> {
+        let mut balancer = LvsWeightedRoundRobin {
+            nodes: NodesContainer::from(nodes),
+            priorities: Priorities::new(),
+            levels: BTreeMap::new(),
+        };
+        balancer.update_weights();
+        balancer
+    }
+
+    /// max and gcd of the weights of each level. O(n * levels)
+    fn update_weights(&mut self) {
+        let priorities: Vec<usize> = self.nodes.levels().keys().copied().collect();
+        self.levels.retain(|priority, _| priorities.contains(priority));
+        for priority in priorities {
+            let level = self.levels.entry(priority).or_default();
+            level.max_weight = self.nodes.max_weight(priority);
+            level.gcd_weight = self.nodes.gcd_weight(priority);
+            for cursor in [&mut level.cursor, &mut level.filtered] {
+                if cursor.current_weight > level.max_weight {
+                    cursor.current_weight = level.max_weight;
+                }
+            }
+        }
+    }
+
+    fn has_candidate(nodes: &NodesContainer<T>, filter: &dyn Fn(&Node<T>) -> bool) -> bool {
+        (0..nodes.len())
+            .filter_map(|index| nodes.get_by_index(index))
+            .any(|node| node.weight > 0 && filter(node))
+    }
+}
+
+impl<T: Hash + Eq + Clone> Balancer<T> for LvsWeightedRoundRobin<T> {
+    fn add_node(&mut self, node: Node<T>) -> Result<(), DuplicatedKeyError> {
+        self.nodes.insert(node)?;
+        self.update_weights();
+        Ok(())
+    }
+
+    fn remove_node(&mut self, id: &T) -> Result<(), NotFoundError> {
+        let removed = self.nodes.remove(id)?;
+        // keep the positions, the next node is the one after the removed node.
+        for level in self.levels.values_mut() {
+            for cursor in [&mut level.cursor, &mut level.filtered] {
+                cursor.index = match cursor.index {
+                    Some(index) if index >= removed => index.checked_sub(1),
+                    index => index,
+                };
+            }
+        }
+        self.update_weights();
+        Ok(())
+    }
+
+    fn contains_id(&mut self, id: &T) -> bool {
+        self.nodes.get_by_id(id).is_some()
+    }
+
+    fn get_node(&self, id: &T) -> Option<&Node<T>> {
+        self.nodes.get_by_id(id)
+    }
+
+    fn get_nodes(&self) -> Vec<&Node<T>> {
+        self.nodes.get_all()
+    }
+
+    fn set_down(&mut self, id: &T, down: bool) -> Result<(), NotFoundError> {
+        self.nodes.set_down(id, down)
+    }
+
+    fn set_overprovisioning_factor(&mut self, factor: Option<f64>) {
+        self.priorities.set_overprovisioning_factor(factor);
+    }
+
+    fn set_panic_threshold(&mut self, threshold: Option<f64>) {
+        self.priorities.set_panic_threshold(threshold);
+    }
+
+    fn next_id(&mut self) -> Option<&T> {
+        self.next().map(|n| &n.id)
+    }
+
+    fn next(&mut self) -> Option<&Node<T>> {
+        let index = selection::next(self, None)?;
+        self.nodes.get_by_index(index)
+    }
+
+    fn next_for(&mut self, ctx: &dyn RequestContext<T>) -> Option<&Node<T>> {
+        let index = selection::next(self, Some(&|node| ctx.accepts(node)))?;
+        self.nodes.get_by_index(index)
+    }
+}
+
+impl<T: Hash + Eq + Clone> LvsWeightedRoundRobin<T> {
+    /// index of the selected node of the level, none if the filter accepts no node.
+    fn select(&mut self, priority: usize, filtered: bool, filter: &dyn Fn(&Node<T>) -> bool) -> Option<usize> {
+        let len = self.nodes.len();
+        let level = self.levels.get_mut(&priority)?;
+        if len == 0 || level.max_weight == 0 {
+            return None;
+        }
+        let (max_weight, gcd_weight) = (level.max_weight, level.gcd_weight);
+        let cursor = if filtered { &mut level.filtered } else { &mut level.cursor };
+        let init = *cursor;
+        let mut checked = false;
+        loop {
+            let index = match cursor.index {
+                Some(index) if index + 1 < len => index + 1,
+                _ => {
+                    // the first wrap without a match, make sure the loop ends.
+                    if !checked {
+                        if !Self::has_candidate(&self.nodes, filter) {
+                            *cursor = init;
+                            return None;
+                        }
+                        checked = true;
+                    }
+                    cursor.current_weight = if cursor.current_weight > gcd_weight {
+                        cursor.current_weight - gcd_weight
+                    } else {
+                        max_weight
+                    };
+                    0
+                }
+            };
+            cursor.index = Some(index);
+            let node = self.nodes.get_by_index(index)?;
+            if node.weight >= cursor.current_weight && filter(node) {
+                return Some(index);
+            }
+        }
+    }
+}
+
+impl<T: Hash + Eq + Clone> Select<T> for LvsWeightedRoundRobin<T> {
+    fn state(&mut self) -> (&NodesContainer<T>, &mut Priorities) {
+        (&self.nodes, &mut self.priorities)
+    }
+
+    fn select_level(&mut self, priority: usize, panic: bool, filter: Option<Filter<T>>) -> Option<usize> {
+        self.select(priority, filter.is_some(), &|node| selection::accepts(node, priority, panic, filter))
+    }
+}
+
+#[cfg(test)]
+mod lvs_weighted_round_robin_test {
+    use crate::{Balancer, Node, Selector};
+    use crate::lvs_weighted_round_robin::LvsWeightedRoundRobin;
+
+    fn map_nodes(array: Vec<(i32, usize)>) -> Vec<Node<i32>> {
+        array.into_iter()
+            .map(|(id, weight)| Node::new(id, weight))
+            .collect()
+    }
+
+    #[test]
+    fn simple() {
+        let mut balancer = LvsWeightedRoundRobin::new(map_nodes(vec![(1, 4), (2, 3), (3, 2)]));
+        let sequence: Vec<i32> = (0..18).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![1, 1, 2, 1, 2, 3, 1, 2, 3, 1, 1, 2, 1, 2, 3, 1, 2, 3]);
+    }
+
+    #[test]
+    fn gcd() {
+        let mut balancer = LvsWeightedRoundRobin::new(map_nodes(vec![(1, 4), (2, 2), (3, 0)]));
+        let sequence: Vec<i32> = (0..6).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![1, 1, 2, 1, 1, 2]);
+    }
+
+    #[test]
+    fn down() {
+        let mut balancer = LvsWeightedRoundRobin::new(map_nodes(vec![(1, 4), (2, 3), (3, 2)]));
+        balancer.set_down(&1, true).unwrap();
+        let sequence: Vec<i32> = (0..5).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![2, 2, 3, 2, 3]);
+
+        balancer.set_down(&2, true).unwrap();
+        balancer.set_down(&3, true).unwrap();
+        assert!(balancer.next_id().is_none());
+        assert!(balancer.next_id().is_none());
+
+        balancer.set_down(&3, false).unwrap();
+        assert_eq!(*balancer.next_id().unwrap(), 3);
+    }
+
+    #[test]
+    fn add_remove() {
+        let mut balancer = LvsWeightedRoundRobin::new(map_nodes(vec![(1, 1), (2, 1), (3, 1)]));
+        assert_eq!(*balancer.next_id().unwrap(), 1);
+        assert_eq!(*balancer.next_id().unwrap(), 2);
+        balancer.remove_node(&2).unwrap();
+        assert_eq!(*balancer.next_id().unwrap(), 3);
+        balancer.add_node(Node::new(4, 2)).unwrap();
+        assert_eq!(*balancer.next_id().unwrap(), 4);
+        assert_eq!(*balancer.next_id().unwrap(), 4);
+        assert_eq!(*balancer.next_id().unwrap(), 1);
+
+        balancer.remove_node(&1).unwrap();
+        balancer.remove_node(&3).unwrap();
+        balancer.remove_node(&4).unwrap();
+        assert!(balancer.next_id().is_none());
+    }
+
+    #[test]
+    fn backup() {
+        let mut balancer = LvsWeightedRoundRobin::new(vec![
+            Node::new(1, 2),
+            Node::new(2, 1).with_backup(true),
+            Node::new(3, 1),
+        ]);
+        for _ in 0..10 {
+            assert_ne!(*balancer.next_id().unwrap(), 2);
+        }
+        balancer.set_down(&1, true).unwrap();
+        balancer.set_down(&3, true).unwrap();
+        assert_eq!(*balancer.next_id().unwrap(), 2);
+        assert_eq!(*balancer.next_id().unwrap(), 2);
+
+        balancer.set_down(&2, true).unwrap();
+        assert!(balancer.next_id().is_none());
+    }
+
+    #[test]
+    fn backup_weights() {
+        // max and gcd of each level, the backup does not change the sequence of the primaries.
+        let mut balancer = LvsWeightedRoundRobin::new(vec![
+            Node::new(1, 4),
+            Node::new(2, 2),
+            Node::new(3, 3).with_backup(true),
+        ]);
+        let sequence: Vec<i32> = (0..6).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![1, 1, 2, 1, 1, 2]);
+
+        balancer.set_down(&1, true).unwrap();
+        balancer.set_down(&2, true).unwrap();
+        let sequence: Vec<i32> = (0..3).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![3, 3, 3]);
+    }
+
+    #[test]
+    fn next_matching() {
+        let mut balancer = LvsWeightedRoundRobin::new(vec![
+            Node::new(1, 1).with_label("version", "v1"),
+            Node::new(2, 1).with_label("version", "v1"),
+            Node::new(3, 1).with_label("version", "v2"),
+            Node::new(4, 1).with_label("version", "v2"),
+        ]);
+        let v2 = Selector::parse("version=v2").unwrap();
+        let mut sequence = Vec::new();
+        let mut matching = Vec::new();
+        for _ in 0..4 {
+            sequence.push(*balancer.next_id().unwrap());
+            matching.push(*balancer.next_matching(&v2).unwrap().get_id());
+        }
+        // filtered selections keep their own position, next() still goes over every node.
+        assert_eq!(sequence, vec![1, 2, 3, 4]);
+        assert_eq!(matching, vec![3, 4, 3, 4]);
+    }
+}
@@ -1,5 +1,4 @@
-use std::collections::hash_map::IterMut;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::Hash;
 
 use crate::errors::{DuplicatedKeyError, NotFoundError};
@@ -8,6 +7,15 @@ use crate::Node;
 pub struct NodesContainer<T: Hash + Eq + Clone> {
     vec: Vec<T>,
     map: HashMap<T, Node<T>>,
+    /// by priority, kept up to date by insert/remove/set_down.
+    levels: BTreeMap<usize, Level>,
+}
+
+/// number of nodes of a priority level.
+#[derive(Clone, Copy, Default)]
+pub struct Level {
+    pub total: usize,
+    pub healthy: usize,
 }
 
 #[allow(dead_code)]
@@ -16,6 +24,7 @@ impl<T: Hash + Eq + Clone> NodesContainer<T> {
         NodesContainer {
             vec: Vec::new(),
             map: HashMap::new(),
+            levels: BTreeMap::new(),
         }
     }
 
@@ -25,15 +34,40 @@ impl<T: Hash + Eq + Clone> NodesContainer<T> {
             .map(|item| {
                 let id = item.id.clone();
                 map.insert(id.clone(), item);
-                return id;
+                id
             })
             .collect();
-        NodesContainer {
+        let mut container = NodesContainer {
             vec: ids,
-            map: map,
+            map,
+            levels: BTreeMap::new(),
+        };
+        for node in container.map.values() {
+            Self::count(&mut container.levels, node, true);
+        }
+        container
+    }
+
+    fn count(levels: &mut BTreeMap<usize, Level>, node: &Node<T>, add: bool) {
+        let level = levels.entry(node.priority).or_default();
+        let healthy = usize::from(!node.down);
+        if add {
+            level.total += 1;
+            level.healthy += healthy;
+        } else {
+            level.total -= 1;
+            level.healthy -= healthy;
+            if level.total == 0 {
+                levels.remove(&node.priority);
+            }
         }
     }
 
+    /// priority levels in order, only levels with nodes.
+    pub fn levels(&self) -> &BTreeMap<usize, Level> {
+        &self.levels
+    }
+
     pub fn len(&self) -> usize {
         self.vec.len()
     }
@@ -41,8 +75,7 @@ impl<T: Hash + Eq + Clone> NodesContainer<T> {
     pub fn get_all(&self) -> Vec<&Node<T>> {
         self.vec
             .iter()
-            .map(|id| self.map.get(id))
-            .flatten()
+            .filter_map(|id| self.map.get(id))
             .collect()
     }
 
@@ -50,6 +83,7 @@ impl<T: Hash + Eq + Clone> NodesContainer<T> {
         if self.map.contains_key(&node.id) {
             return Err(DuplicatedKeyError);
         }
+        Self::count(&mut self.levels, &node, true);
         let id = node.id.clone();
         self.vec.push(id.clone());
         self.map.insert(id, node);
@@ -62,7 +96,8 @@ impl<T: Hash + Eq + Clone> NodesContainer<T> {
             None => {
                 Err(NotFoundError)
             }
-            Some(_) => {
+            Some(node) => {
+                Self::count(&mut self.levels, &node, false);
                 if let Some(index) = self.vec.iter().position(|x| x == id) {
                     self.vec.remove(index);
                     Ok(index)
@@ -73,40 +108,75 @@ impl<T: Hash + Eq + Clone> NodesContainer<T> {
         }
     }
 
-    /// O(1)
+    /// O(1), use set_down to change the down flag.
     pub fn get_mut_by_id(&mut self, id: &T) -> Option<&mut Node<T>> {
-        self.map.get_mut(&id)
+        self.map.get_mut(id)
     }
 
     pub fn get_by_id(&self, id: &T) -> Option<&Node<T>> {
-        self.map.get(&id)
+        self.map.get(id)
     }
 
     /// O(1)
     pub fn get_mut_by_index(&mut self, index: usize) -> Option<&mut Node<T>> {
-        self.vec.get(index).map(|id| {
+        self.vec.get(index).and_then(|id| {
             self.map.get_mut(id)
-        }).flatten()
+        })
     }
 
     pub fn get_by_index(&self, index: usize) -> Option<&Node<T>> {
-        self.vec.get(index).map(|id| {
+        self.vec.get(index).and_then(|id| {
             self.map.get(id)
-        }).flatten()
+        })
     }
-    /// O(1)
-    pub fn set_down(&mut self, id: &T, down: bool) -> Result<(), NotFoundError> {
-        self.get_mut_by_id(id)
-            .map(|node| {
-                node.down = down
-            })
-            .ok_or(NotFoundError)
+    /// O(n), of the nodes of a priority level.
+    pub fn max_weight(&self, priority: usize) -> usize {
+        self.map
+            .values()
+            .filter(|node| node.priority == priority)
+            .map(|node| node.weight)
+            .max()
+            .unwrap_or(0)
     }
 
+    /// O(n), greatest common divisor of positive weights of a priority level, 1 if there is none.
+    pub fn gcd_weight(&self, priority: usize) -> usize {
+        let gcd = self.map
+            .values()
+            .filter(|node| node.priority == priority)
+            .map(|node| node.weight)
+            .filter(|weight| *weight > 0)
+            .fold(0, |a, b| {
+                let (mut a, mut b) = (a, b);
+                while b != 0 {
+                    (a, b) = (b, a % b);
+                }
+                a
+            });
+        gcd.max(1)
+    }
 
-    pub fn iter_mut(&mut self) -> IterMut<'_, T, Node<T>> {
-        self.map.iter_mut()
+    /// O(n)
+    pub fn index_of(&self, id: &T) -> Option<usize> {
+        self.vec.iter().position(|x| x == id)
     }
+
+    /// O(1)
+    pub fn set_down(&mut self, id: &T, down: bool) -> Result<(), NotFoundError> {
+        let node = self.map.get_mut(id).ok_or(NotFoundError)?;
+        if node.down != down {
+            node.down = down;
+            if let Some(level) = self.levels.get_mut(&node.priority) {
+                if down {
+                    level.healthy -= 1;
+                } else {
+                    level.healthy += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
 }
 
 // impl<T: Hash + Eq + Clone> NodesContainer<T> {
@@ -165,10 +235,67 @@ mod nodes_test {
         assert_eq!(nodes.get_by_index(0).unwrap().id, 2);
         assert!(nodes.set_down(&2, true).is_ok());
 
-        assert_eq!(nodes.get_by_index(0).unwrap().down, true);
+        assert!(nodes.get_by_index(0).unwrap().down);
+
+        assert!(nodes.insert(Node::new(5, 6)).is_ok());
+        assert!(nodes.insert(Node::new(6, 0)).is_ok());
+        assert_eq!(nodes.max_weight(0), 6);
+        assert_eq!(nodes.gcd_weight(0), 1);
+        assert!(nodes.remove(&2).is_ok());
+        assert!(nodes.remove(&3).is_ok());
+        assert!(nodes.remove(&4).is_ok());
+        assert!(nodes.insert(Node::new(7, 4)).is_ok());
+        assert_eq!(nodes.gcd_weight(0), 2);
+        assert_eq!(nodes.index_of(&7), Some(2));
+        assert_eq!(NodesContainer::<i32>::new().gcd_weight(0), 1);
 
         // for item in &nodes {
         //     println!("{}", item.id);
         // }
     }
+
+    #[test]
+    fn levels() {
+        let mut nodes = NodesContainer::from(vec![
+            Node::new_with_default_weight(1),
+            Node::new_with_default_weight(2).with_priority(1),
+            Node::new_with_default_weight(3).with_priority(1),
+        ]);
+        let counts = |nodes: &NodesContainer<i32>| -> Vec<(usize, usize, usize)> {
+            nodes.levels()
+                .iter()
+                .map(|(priority, level)| (*priority, level.total, level.healthy))
+                .collect()
+        };
+        assert_eq!(counts(&nodes), vec![(0, 1, 1), (1, 2, 2)]);
+
+        nodes.set_down(&2, true).unwrap();
+        nodes.set_down(&2, true).unwrap();
+        assert_eq!(counts(&nodes), vec![(0, 1, 1), (1, 2, 1)]);
+
+        nodes.remove(&1).unwrap();
+        nodes.remove(&2).unwrap();
+        assert!(nodes.insert(Node::new_with_default_weight(4).with_priority(5)).is_ok());
+        assert_eq!(counts(&nodes), vec![(1, 1, 1), (5, 1, 1)]);
+        assert!(nodes.insert(Node::new(5, 6).with_priority(5)).is_ok());
+        assert_eq!((nodes.max_weight(1), nodes.max_weight(5), nodes.max_weight(0)), (1, 6, 0));
+        assert_eq!((nodes.gcd_weight(5), nodes.gcd_weight(0)), (1, 1));
+    }
+
+    #[test]
+    fn labels() {
+        let mut nodes: NodesContainer<i32> = NodesContainer::new();
+        nodes.insert(Node::new(1, 1).with_zone("a").with_label("version", "1.2")).unwrap();
+        nodes.insert(Node::new(2, 1).with_zone("b").with_label("canary", "true")).unwrap();
+        nodes.remove(&1).unwrap();
+        nodes.insert(Node::new(3, 1)).unwrap();
+        nodes.set_down(&2, true).unwrap();
+
+        let node = nodes.get_by_id(&2).unwrap();
+        assert_eq!(node.get_zone(), Some("b"));
+        assert_eq!(node.get_label("canary"), Some("true"));
+        assert_eq!(node.get_label("version"), None);
+        assert_eq!(node.clone().get_labels().len(), 2);
+        assert!(nodes.get_by_id(&3).unwrap().get_labels().is_empty());
+    }
 }
\ No newline at end of file
@@ -1,30 +1,121 @@
+use std::collections::BTreeMap;
 use std::hash::Hash;
 
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
 
-use crate::{Balancer, Node};
+use crate::{Balancer, Node, RequestContext};
+use crate::alias_table::AliasTable;
 use crate::errors::{DuplicatedKeyError, NotFoundError};
 use crate::nodes::NodesContainer;
+use crate::selection::{self, Filter, Priorities, Select};
 
-pub struct Random<T: Hash + Eq + Clone> {
+/// Default rng of `Random`, delegates to `rand::thread_rng()` on every call.
+#[derive(Clone, Copy, Default)]
+pub struct ThreadRandom;
+
+impl RngCore for ThreadRandom {
+    fn next_u32(&mut self) -> u32 {
+        rand::thread_rng().next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand::thread_rng().next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand::thread_rng().fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        rand::thread_rng().try_fill_bytes(dest)
+    }
+}
+
+/// rejection sampling tries before picking from the accepted nodes directly.
+const TRIES: usize = 16;
+
+/// picks nodes with probability proportional to their weight.
+pub struct Random<T: Hash + Eq + Clone, R: RngCore = ThreadRandom> {
     nodes: NodesContainer<T>,
+    priorities: Priorities,
+    rng: R,
+    /// by priority, rebuilt when nodes change.
+    levels: BTreeMap<usize, LevelTables>,
+}
+
+/// alias tables of a priority level.
+struct LevelTables {
+    /// indexes of live nodes.
+    live: Vec<usize>,
+    /// weights of `live`.
+    table: AliasTable,
+    /// indexes of all nodes, for panic.
+    all: Vec<usize>,
+    /// weights of `all`.
+    all_table: AliasTable,
 }
 
 impl<T: Hash + Eq + Clone> Random<T> {
     pub fn new(nodes: Vec<Node<T>>) -> Random<T> {
-        Random {
+        Random::with_rng(nodes, ThreadRandom)
+    }
+}
+
+impl<T: Hash + Eq + Clone> Random<T, StdRng> {
+    /// reproducible sequence for the same seed and nodes.
+    pub fn with_seed(nodes: Vec<Node<T>>, seed: u64) -> Random<T, StdRng> {
+        Random::with_rng(nodes, StdRng::seed_from_u64(seed))
+    }
+}
+
+impl<T: Hash + Eq + Clone, R: RngCore> Random<T, R> {
+    pub fn with_rng(nodes: Vec<Node<T>>, rng: R) -> Random<T, R> {
+        let mut balancer = Random {
             nodes: NodesContainer::from(nodes),
+            priorities: Priorities::new(),
+            rng,
+            levels: BTreeMap::new(),
+        };
+        balancer.rebuild();
+        balancer
+    }
+
+    /// O(n)
+    fn rebuild(&mut self) {
+        let nodes = self.nodes.get_all();
+        let mut levels: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (index, node) in nodes.iter().enumerate() {
+            levels.entry(node.priority).or_default().push(index);
         }
+        self.levels = levels
+            .into_iter()
+            .map(|(priority, all)| {
+                let live: Vec<usize> = all.iter().copied().filter(|index| !nodes[*index].is_down()).collect();
+                let weights: Vec<usize> = live.iter().map(|index| nodes[*index].weight).collect();
+                let all_weights: Vec<usize> = all.iter().map(|index| nodes[*index].weight).collect();
+                (priority, LevelTables {
+                    live,
+                    table: AliasTable::new(&weights),
+                    all,
+                    all_table: AliasTable::new(&all_weights),
+                })
+            })
+            .collect();
     }
 }
 
-impl<T: Hash + Eq + Clone> Balancer<T> for Random<T> {
+impl<T: Hash + Eq + Clone, R: RngCore> Balancer<T> for Random<T, R> {
     fn add_node(&mut self, node: Node<T>) -> Result<(), DuplicatedKeyError> {
-        self.nodes.insert(node)
+        self.nodes.insert(node)?;
+        self.rebuild();
+        Ok(())
     }
 
     fn remove_node(&mut self, id: &T) -> Result<(), NotFoundError> {
-        self.nodes.remove(id).map(|_| ())
+        self.nodes.remove(id)?;
+        self.rebuild();
+        Ok(())
     }
 
     fn contains_id(&mut self, id: &T) -> bool {
@@ -40,7 +131,17 @@ impl<T: Hash + Eq + Clone> Balancer<T> for Random<T> {
     }
 
     fn set_down(&mut self, id: &T, down: bool) -> Result<(), NotFoundError> {
-        self.nodes.set_down(id, down)
+        self.nodes.set_down(id, down)?;
+        self.rebuild();
+        Ok(())
+    }
+
+    fn set_overprovisioning_factor(&mut self, factor: Option<f64>) {
+        self.priorities.set_overprovisioning_factor(factor);
+    }
+
+    fn set_panic_threshold(&mut self, threshold: Option<f64>) {
+        self.priorities.set_panic_threshold(threshold);
     }
 
     fn next_id(&mut self) -> Option<&T> {
@@ -48,53 +149,139 @@ impl<T: Hash + Eq + Clone> Balancer<T> for Random<T> {
     }
 
     fn next(&mut self) -> Option<&Node<T>> {
-        let len = self.nodes.len();
-        if len == 0 {
-            return None;
-        }
-
-        let mut index = rand::thread_rng().gen_range(0..len);
-        let init = index;
-        //todo
-        while let Some(node) = self.nodes.get_by_index(index) {
-            if node.is_down() {
-                index = if index >= len - 1 {
-                    0
-                } else {
-                    index + 1
-                };
-                // all is down.
-                if index == init {
-                    break;
+        let index = selection::next(self, None)?;
+        self.nodes.get_by_index(index)
+    }
+
+    fn next_for(&mut self, ctx: &dyn RequestContext<T>) -> Option<&Node<T>> {
+        let index = selection::next(self, Some(&|node| ctx.accepts(node)))?;
+        self.nodes.get_by_index(index)
+    }
+}
+
+impl<T: Hash + Eq + Clone, R: RngCore> Random<T, R> {
+    /// index of the selected node of the level, none if the filter accepts no node.
+    /// samples the live nodes of the level, or all of them if `panic`, the filter only rejects
+    /// nodes for the request (saturated, rate limited, not accepted).
+    fn select(&mut self, priority: usize, panic: bool, filter: &dyn Fn(&Node<T>) -> bool) -> Option<usize> {
+        let level = self.levels.get(&priority)?;
+        let (indexes, table) = if panic {
+            (&level.all, &level.all_table)
+        } else {
+            (&level.live, &level.table)
+        };
+        for _ in 0..TRIES {
+            let index = indexes[table.sample(&mut self.rng)?];
+            if self.nodes.get_by_index(index).is_some_and(filter) {
+                return Some(index);
+            }
+        }
+        // most of the weight is not accepted. O(level size)
+        let mut accepted = Vec::new();
+        let mut weights = Vec::new();
+        for &index in indexes {
+            if let Some(node) = self.nodes.get_by_index(index) {
+                if filter(node) {
+                    accepted.push(index);
+                    weights.push(node.weight);
                 }
-                continue;
             }
-            return Some(node);
         }
-        return None;
+        AliasTable::new(&weights)
+            .sample(&mut self.rng)
+            .map(|index| accepted[index])
+    }
+}
+
+impl<T: Hash + Eq + Clone, R: RngCore> Select<T> for Random<T, R> {
+    fn state(&mut self) -> (&NodesContainer<T>, &mut Priorities) {
+        (&self.nodes, &mut self.priorities)
+    }
+
+    fn select_level(&mut self, priority: usize, panic: bool, filter: Option<Filter<T>>) -> Option<usize> {
+        self.select(priority, panic, &|node| selection::accepts(node, priority, panic, filter))
     }
 }
 
 
 #[cfg(test)]
 mod random_test {
+    use std::collections::HashMap;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
     use crate::{Balancer, Node};
     use crate::random::Random;
 
     #[test]
     fn simple() {
         let nodes = vec![1, 2, 3, 4, 5];
-        let nodes = nodes.into_iter().map(|id| Node::new_with_default_weight(id)).collect();
+        let nodes = nodes.into_iter().map(Node::new_with_default_weight).collect();
         let mut balancer = Random::new(nodes);
         for _ in 0..50 {
             assert!(balancer.next().is_some());
         }
     }
 
+    #[test]
+    fn seed() {
+        let nodes: Vec<Node<i32>> = (0..10).map(Node::new_with_default_weight).collect();
+        let mut first = Random::with_seed(nodes.clone(), 42);
+        let mut second = Random::with_seed(nodes.clone(), 42);
+        let first: Vec<i32> = (0..50).map(|_| *first.next_id().unwrap()).collect();
+        let second: Vec<i32> = (0..50).map(|_| *second.next_id().unwrap()).collect();
+        assert_eq!(first, second);
+
+        let mut other = Random::with_rng(nodes, StdRng::seed_from_u64(43));
+        let other: Vec<i32> = (0..50).map(|_| *other.next_id().unwrap()).collect();
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn weighted() {
+        let nodes = vec![Node::new(1, 1), Node::new(2, 3), Node::new(3, 0)];
+        let mut balancer = Random::with_seed(nodes, 42);
+        let mut counts = HashMap::from([(1, 0), (2, 0), (3, 0)]);
+        for _ in 0..10000 {
+            *counts.get_mut(balancer.next_id().unwrap()).unwrap() += 1;
+        }
+        assert_eq!(counts[&3], 0);
+        assert!((2200..2800).contains(&counts[&1]));
+        assert!((7200..7800).contains(&counts[&2]));
+
+        balancer.add_node(Node::new(4, 4)).unwrap();
+        balancer.remove_node(&2).unwrap();
+        let mut counts = HashMap::from([(1, 0), (3, 0), (4, 0)]);
+        for _ in 0..10000 {
+            *counts.get_mut(balancer.next_id().unwrap()).unwrap() += 1;
+        }
+        assert_eq!(counts[&3], 0);
+        assert!((1700..2300).contains(&counts[&1]));
+        assert!((7700..8300).contains(&counts[&4]));
+    }
+
+    #[test]
+    fn down_uniform() {
+        let nodes = (1..=4).map(Node::new_with_default_weight).collect();
+        let mut balancer = Random::with_seed(nodes, 42);
+        balancer.set_down(&2, true).unwrap();
+        let mut counts = HashMap::from([(1, 0), (3, 0), (4, 0)]);
+        for _ in 0..9000 {
+            *counts.get_mut(balancer.next_id().unwrap()).unwrap() += 1;
+        }
+        for count in counts.values() {
+            assert!((2700..3300).contains(count));
+        }
+
+        balancer.set_down(&2, false).unwrap();
+        assert!((0..100).any(|_| *balancer.next_id().unwrap() == 2));
+    }
+
     #[test]
     fn down() {
         let nodes = vec![1, 2];
-        let nodes = nodes.into_iter().map(|id| Node::new_with_default_weight(id)).collect();
+        let nodes = nodes.into_iter().map(Node::new_with_default_weight).collect();
         let mut balancer = Random::new(nodes);
         assert!(balancer.next().is_some());
 
@@ -107,4 +294,98 @@ mod random_test {
         assert!(balancer.next_id().is_none());
         assert!(balancer.next_id().is_none());
     }
+
+    #[test]
+    fn backup() {
+        let nodes = vec![
+            Node::new(1, 1),
+            Node::new(2, 10).with_backup(true),
+            Node::new(3, 1),
+            Node::new(4, 10).with_backup(true),
+        ];
+        let mut balancer = Random::with_seed(nodes, 42);
+        for _ in 0..100 {
+            assert!(!balancer.next().unwrap().is_backup());
+        }
+
+        balancer.set_down(&1, true).unwrap();
+        for _ in 0..100 {
+            assert_eq!(*balancer.next_id().unwrap(), 3);
+        }
+
+        balancer.set_down(&3, true).unwrap();
+        let mut counts = HashMap::from([(2, 0), (4, 0)]);
+        for _ in 0..1000 {
+            *counts.get_mut(balancer.next_id().unwrap()).unwrap() += 1;
+        }
+        assert!(counts[&2] > 400 && counts[&4] > 400);
+
+        balancer.set_down(&2, true).unwrap();
+        balancer.set_down(&4, true).unwrap();
+        assert!(balancer.next_id().is_none());
+    }
+
+    #[test]
+    fn levels() {
+        // the heavy backup node is not in the table of the first level.
+        let nodes = vec![Node::new(1, 1), Node::new(2, 3), Node::new(3, 100).with_priority(1)];
+        let mut balancer = Random::with_seed(nodes, 42);
+        let mut counts = HashMap::from([(1, 0), (2, 0), (3, 0)]);
+        for _ in 0..10000 {
+            *counts.get_mut(balancer.next_id().unwrap()).unwrap() += 1;
+        }
+        assert_eq!(counts[&3], 0);
+        assert!((2200..2800).contains(&counts[&1]));
+
+        balancer.set_down(&1, true).unwrap();
+        balancer.set_down(&2, true).unwrap();
+        assert!((0..100).all(|_| *balancer.next_id().unwrap() == 3));
+        balancer.remove_node(&3).unwrap();
+        assert!(balancer.next_id().is_none());
+    }
+
+    #[test]
+    fn panic() {
+        let nodes = (1..=10).map(Node::new_with_default_weight).collect();
+        let mut balancer = Random::with_seed(nodes, 42);
+        balancer.set_panic_threshold(Some(0.5));
+        for id in 1..=8 {
+            balancer.set_down(&id, true).unwrap();
+        }
+        let mut counts = HashMap::new();
+        for _ in 0..10000 {
+            *counts.entry(*balancer.next_id().unwrap()).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 10);
+        assert!(counts.values().all(|count| (800..1200).contains(count)));
+
+        balancer.set_down(&9, true).unwrap();
+        balancer.set_down(&10, true).unwrap();
+        assert!(balancer.next_id().is_some());
+
+        balancer.set_panic_threshold(None);
+        assert!(balancer.next_id().is_none());
+    }
+
+    #[test]
+    fn next_excluding() {
+        let nodes = vec![Node::new(1, 100), Node::new(2, 1), Node::new(3, 1)];
+        let mut balancer = Random::with_seed(nodes, 42);
+        for _ in 0..100 {
+            assert_ne!(*balancer.next_excluding(&[1]).unwrap().get_id(), 1);
+        }
+        assert_eq!(*balancer.next_excluding(&[1, 3]).unwrap().get_id(), 2);
+        assert!(balancer.next_excluding(&[1, 2, 3]).is_none());
+    }
+
+    #[test]
+    fn next_hedged() {
+        let nodes = vec![Node::new(1, 100), Node::new(2, 1), Node::new(3, 1)];
+        let mut balancer = Random::with_seed(nodes, 42);
+        for _ in 0..100 {
+            let mut ids: Vec<i32> = balancer.next_hedged(3, None).into_iter().map(|node| node.id).collect();
+            ids.sort();
+            assert_eq!(ids, vec![1, 2, 3]);
+        }
+    }
 }
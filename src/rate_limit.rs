@@ -0,0 +1,127 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Source of time for rate limits.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// `Instant::now()`
+#[derive(Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock that only moves when advanced, for tests.
+pub struct ManualClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+}
+
+/// Starts full, refills `rate` tokens per second up to `burst`, one token per request.
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// panics if `rate` is negative, infinite or NaN.
+    pub fn new(rate: f64, burst: usize, now: Instant) -> TokenBucket {
+        assert!(
+            rate.is_finite() && rate >= 0.0,
+            "rate limit must be a finite non negative number of requests per second, got {}",
+            rate
+        );
+        TokenBucket {
+            rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    pub fn has_token(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+
+    pub fn take(&mut self, now: Instant) -> bool {
+        if !self.has_token(now) {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod rate_limit_test {
+    use std::time::{Duration, Instant};
+
+    use crate::rate_limit::TokenBucket;
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 3, start);
+        assert!((0..3).all(|_| bucket.take(start)));
+        assert!(!bucket.take(start));
+
+        let now = start + Duration::from_millis(500);
+        assert!(bucket.take(now));
+        assert!(!bucket.take(now));
+
+        // never more than burst.
+        let now = now + Duration::from_secs(60);
+        assert_eq!((0..10).filter(|_| bucket.take(now)).count(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "rate limit must be")]
+    fn nan_rate() {
+        TokenBucket::new(f64::NAN, 1, Instant::now());
+    }
+
+    #[test]
+    #[should_panic(expected = "rate limit must be")]
+    fn negative_rate() {
+        crate::Node::new_with_default_weight(1).with_rate_limit(-1.0, 1);
+    }
+}
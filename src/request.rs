@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+
+use crate::Node;
+
+/// What a strategy can know about a request, see `Balancer::next_for`.
+/// every method is optional, a strategy ignores what it does not use.
+pub trait RequestContext<T: Hash + Eq + Clone> {
+    /// key of hashing strategies (session, user, tenant, path ...).
+    fn hash_key(&self) -> Option<&str> {
+        None
+    }
+
+    fn client_addr(&self) -> Option<IpAddr> {
+        None
+    }
+
+    fn header(&self, _name: &str) -> Option<&str> {
+        None
+    }
+
+    /// nodes allowed to serve the request.
+    fn accepts(&self, _node: &Node<T>) -> bool {
+        true
+    }
+}
+
+/// `RequestContext` without filter.
+#[derive(Clone, Debug, Default)]
+pub struct Request {
+    hash_key: Option<String>,
+    client_addr: Option<IpAddr>,
+    /// names in lower case.
+    headers: HashMap<String, String>,
+}
+
+impl Request {
+    pub fn new() -> Request {
+        Request::default()
+    }
+
+    pub fn with_hash_key(mut self, key: impl Into<String>) -> Request {
+        self.hash_key = Some(key.into());
+        self
+    }
+
+    pub fn with_client_addr(mut self, addr: IpAddr) -> Request {
+        self.client_addr = Some(addr);
+        self
+    }
+
+    /// header names are case insensitive.
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Request {
+        self.headers.insert(name.to_ascii_lowercase(), value.into());
+        self
+    }
+
+    pub fn get_hash_key(&self) -> Option<&str> {
+        self.hash_key.as_deref()
+    }
+
+    pub fn get_client_addr(&self) -> Option<IpAddr> {
+        self.client_addr
+    }
+
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+}
+
+impl<T: Hash + Eq + Clone> RequestContext<T> for Request {
+    fn hash_key(&self) -> Option<&str> {
+        self.get_hash_key()
+    }
+
+    fn client_addr(&self) -> Option<IpAddr> {
+        self.get_client_addr()
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.get_header(name)
+    }
+}
+
+/// rejects the nodes already tried, see `Balancer::next_excluding`.
+pub(crate) struct Excluding<'a, T>(pub &'a [T]);
+
+impl<T: Hash + Eq + Clone> RequestContext<T> for Excluding<'_, T> {
+    fn accepts(&self, node: &Node<T>) -> bool {
+        !self.0.contains(node.get_id())
+    }
+}
+
+/// true if two nodes are likely to fail together (same zone, rack, host ...).
+pub type Correlated<'a, T> = &'a dyn Fn(&Node<T>, &Node<T>) -> bool;
+
+/// rejects the nodes already picked for a hedged request, see `Balancer::next_hedged`.
+/// strict also rejects the nodes correlated with a picked node.
+pub(crate) struct Hedge<'a, T: Hash + Eq + Clone> {
+    pub picked: &'a [Node<T>],
+    pub correlated: Option<Correlated<'a, T>>,
+    pub strict: bool,
+}
+
+impl<T: Hash + Eq + Clone> RequestContext<T> for Hedge<'_, T> {
+    fn accepts(&self, node: &Node<T>) -> bool {
+        self.picked.iter().all(|picked| {
+            picked.id != node.id
+                && !(self.strict && self.correlated.is_some_and(|correlated| correlated(picked, node)))
+        })
+    }
+}
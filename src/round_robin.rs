@@ -1,19 +1,33 @@
+use std::collections::BTreeMap;
 use std::hash::Hash;
 
-use crate::{Balancer, Node};
+use crate::{Balancer, Node, RequestContext};
 use crate::errors::{DuplicatedKeyError, NotFoundError};
 use crate::nodes::NodesContainer;
+use crate::selection::{self, Filter, Priorities, Select};
 
 pub struct RoundRobin<T: Hash + Eq + Clone> {
     nodes: NodesContainer<T>,
-    index: usize,
+    priorities: Priorities,
+    /// by priority level.
+    cursors: BTreeMap<usize, Cursors>,
+}
+
+/// positions of the next node of a priority level.
+#[derive(Default)]
+struct Cursors {
+    next: usize,
+    /// of the selections with a request filter (`next_for`, `next_matching`...),
+    /// they do not move the position of `next()`.
+    filtered: usize,
 }
 
 impl<T: Hash + Eq + Clone> RoundRobin<T> {
     pub fn new(nodes: Vec<Node<T>>) -> RoundRobin<T> {
         RoundRobin {
             nodes: NodesContainer::from(nodes),
-            index: 0,
+            priorities: Priorities::new(),
+            cursors: BTreeMap::new(),
         }
     }
 }
@@ -24,18 +38,20 @@ impl<T: Hash + Eq + Clone> Balancer<T> for RoundRobin<T> {
     }
 
     fn remove_node(&mut self, id: &T) -> Result<(), NotFoundError> {
-        self.nodes.remove(id)
-            .map(|index| {
-                if self.index > index {
-                    self.index -= 1;
-                } else if self.index == index {
-                    self.index = if self.index >= self.nodes.len() - 1 {
-                        0
-                    } else {
-                        self.index + 1
-                    };
-                }
-            })
+        let index = self.nodes.remove(id)?;
+        let levels = self.nodes.levels();
+        self.cursors.retain(|priority, _| levels.contains_key(priority));
+        for cursor in self.cursors.values_mut().flat_map(|cursors| [&mut cursors.next, &mut cursors.filtered]) {
+            // a cursor is the position of the next node: when the next node is removed, the
+            // node after it shifts to the same position and is next.
+            if *cursor > index {
+                *cursor -= 1;
+            } else if *cursor >= self.nodes.len() {
+                // the removed node was the next one and the last one.
+                *cursor = 0;
+            }
+        }
+        Ok(())
     }
 
     fn contains_id(&mut self, id: &T) -> bool {
@@ -54,45 +70,80 @@ impl<T: Hash + Eq + Clone> Balancer<T> for RoundRobin<T> {
         self.nodes.set_down(id, down)
     }
 
+    fn set_overprovisioning_factor(&mut self, factor: Option<f64>) {
+        self.priorities.set_overprovisioning_factor(factor);
+    }
+
+    fn set_panic_threshold(&mut self, threshold: Option<f64>) {
+        self.priorities.set_panic_threshold(threshold);
+    }
+
     fn next_id(&mut self) -> Option<&T> {
         self.next().map(|n| &n.id)
     }
-    
+
     fn next(&mut self) -> Option<&Node<T>> {
+        let index = selection::next(self, None)?;
+        self.nodes.get_by_index(index)
+    }
+
+    fn next_for(&mut self, ctx: &dyn RequestContext<T>) -> Option<&Node<T>> {
+        let index = selection::next(self, Some(&|node| ctx.accepts(node)))?;
+        self.nodes.get_by_index(index)
+    }
+}
+
+impl<T: Hash + Eq + Clone> RoundRobin<T> {
+    /// index of the selected node of the level, none if the filter accepts no node.
+    fn select(&mut self, priority: usize, filtered: bool, filter: &dyn Fn(&Node<T>) -> bool) -> Option<usize> {
         let len = self.nodes.len();
         if len == 0 {
             return None;
         }
-        let init = self.index;
-        //todo
-        while let Some(node) = self.nodes.get_by_index(self.index) {
-            self.index = if self.index >= len - 1 {
+        let cursors = self.cursors.entry(priority).or_default();
+        let cursor = if filtered { &mut cursors.filtered } else { &mut cursors.next };
+        let init = *cursor;
+        while let Some(node) = self.nodes.get_by_index(*cursor) {
+            let index = *cursor;
+            *cursor = if *cursor >= len - 1 {
                 0
             } else {
-                self.index + 1
+                *cursor + 1
             };
-            if node.is_down() {
-                // all is down.
-                if self.index == init {
+            if !filter(node) {
+                // none is accepted.
+                if *cursor == init {
                     break;
                 }
                 continue;
             }
-            return Some(node);
+            return Some(index);
         }
-        return None;
+        None
+    }
+}
+
+impl<T: Hash + Eq + Clone> Select<T> for RoundRobin<T> {
+    fn state(&mut self) -> (&NodesContainer<T>, &mut Priorities) {
+        (&self.nodes, &mut self.priorities)
+    }
+
+    fn select_level(&mut self, priority: usize, panic: bool, filter: Option<Filter<T>>) -> Option<usize> {
+        self.select(priority, filter.is_some(), &|node| selection::accepts(node, priority, panic, filter))
     }
 }
 
 #[cfg(test)]
 mod round_robin_test {
-    use crate::{Balancer, Node};
+    use std::collections::HashMap;
+
+    use crate::{AcquireError, Balancer, Node, Request, RequestContext, Selector};
     use crate::round_robin::RoundRobin;
 
     #[test]
     fn simple() {
         let nodes = vec![1, 2, 3, 4, 5];
-        let nodes = nodes.into_iter().map(|id| Node::new_with_default_weight(id)).collect();
+        let nodes = nodes.into_iter().map(Node::new_with_default_weight).collect();
         let mut balancer = RoundRobin::new(nodes);
         for i in 0..20 {
             assert_eq!((i % 5) + 1, balancer.next().unwrap().id);
@@ -102,7 +153,7 @@ mod round_robin_test {
     #[test]
     fn add_node() {
         let nodes = vec![1, 2, 3];
-        let nodes = nodes.into_iter().map(|id| Node::new_with_default_weight(id)).collect();
+        let nodes = nodes.into_iter().map(Node::new_with_default_weight).collect();
         let mut balancer = RoundRobin::new(nodes);
         for i in 0..10 {
             let id = balancer.next().unwrap().id;
@@ -116,7 +167,7 @@ mod round_robin_test {
     #[test]
     fn remove_node() {
         let nodes = vec![1, 2, 3];
-        let nodes = nodes.into_iter().map(|id| Node::new_with_default_weight(id)).collect();
+        let nodes = nodes.into_iter().map(Node::new_with_default_weight).collect();
         let mut balancer = RoundRobin::new(nodes);
 
         assert_eq!(*balancer.next_id().unwrap(), 1);
@@ -124,12 +175,25 @@ mod round_robin_test {
         assert_eq!(*balancer.next_id().unwrap(), 2);
         balancer.remove_node(&3).unwrap();
         assert_eq!(*balancer.next_id().unwrap(), 2);
+
+        // the node after the removed one is next, not skipped.
+        balancer.add_node(Node::new_with_default_weight(4)).unwrap();
+        balancer.add_node(Node::new_with_default_weight(5)).unwrap();
+        assert_eq!(*balancer.next_id().unwrap(), 2);
+        balancer.remove_node(&4).unwrap();
+        assert_eq!(*balancer.next_id().unwrap(), 5);
+        assert_eq!(*balancer.next_id().unwrap(), 2);
+        balancer.remove_node(&5).unwrap();
+        assert_eq!(*balancer.next_id().unwrap(), 2);
+        // removing the only node, the next one.
+        balancer.remove_node(&2).unwrap();
+        assert!(balancer.next_id().is_none());
     }
 
     #[test]
     fn down() {
         let nodes = vec![1, 2, 3];
-        let nodes = nodes.into_iter().map(|id| Node::new_with_default_weight(id)).collect();
+        let nodes = nodes.into_iter().map(Node::new_with_default_weight).collect();
         let mut balancer = RoundRobin::new(nodes);
 
         balancer.set_down(&1, true).unwrap();
@@ -143,4 +207,219 @@ mod round_robin_test {
         assert!(balancer.next_id().is_none());
         assert!(balancer.next_id().is_none());
     }
+
+    #[test]
+    fn backup() {
+        let mut balancer = RoundRobin::new(vec![
+            Node::new_with_default_weight(1),
+            Node::new_with_default_weight(2).with_backup(true),
+            Node::new_with_default_weight(3),
+            Node::new_with_default_weight(4).with_backup(true),
+        ]);
+        assert!(balancer.get_node(&2).unwrap().is_backup());
+        assert_eq!(*balancer.next_id().unwrap(), 1);
+        assert_eq!(*balancer.next_id().unwrap(), 3);
+        assert_eq!(*balancer.next_id().unwrap(), 1);
+
+        balancer.set_down(&1, true).unwrap();
+        assert_eq!(*balancer.next_id().unwrap(), 3);
+        assert_eq!(*balancer.next_id().unwrap(), 3);
+
+        balancer.set_down(&3, true).unwrap();
+        // the backup level has its own position.
+        assert_eq!(*balancer.next_id().unwrap(), 2);
+        assert_eq!(*balancer.next_id().unwrap(), 4);
+        assert_eq!(*balancer.next_id().unwrap(), 2);
+
+        balancer.set_down(&1, false).unwrap();
+        assert_eq!(*balancer.next_id().unwrap(), 1);
+
+        balancer.set_down(&1, true).unwrap();
+        balancer.set_down(&2, true).unwrap();
+        balancer.set_down(&4, true).unwrap();
+        assert!(balancer.next_id().is_none());
+    }
+
+    #[test]
+    fn spill_keeps_rotation() {
+        let mut balancer = RoundRobin::new(vec![
+            Node::new_with_default_weight(1),
+            Node::new_with_default_weight(2),
+            Node::new_with_default_weight(3),
+            Node::new_with_default_weight(4),
+            Node::new_with_default_weight(5).with_priority(1),
+        ]);
+        balancer.set_down(&3, true).unwrap();
+        balancer.set_down(&4, true).unwrap();
+        balancer.set_overprovisioning_factor(Some(1.4));
+        let mut counts = HashMap::new();
+        for _ in 0..1000 {
+            *counts.entry(*balancer.next_id().unwrap()).or_insert(0) += 1;
+        }
+        assert_eq!(counts, HashMap::from([(1, 350), (2, 350), (5, 300)]));
+    }
+
+    #[test]
+    fn priority() {
+        let mut balancer = RoundRobin::new(vec![
+            Node::new_with_default_weight(1),
+            Node::new_with_default_weight(2),
+            Node::new_with_default_weight(3).with_priority(1),
+            Node::new_with_default_weight(4).with_priority(2),
+        ]);
+        assert_eq!(balancer.get_node(&3).unwrap().get_priority(), 1);
+        balancer.set_down(&1, true).unwrap();
+        balancer.set_down(&2, true).unwrap();
+        assert_eq!(*balancer.next_id().unwrap(), 3);
+        balancer.set_down(&3, true).unwrap();
+        assert_eq!(*balancer.next_id().unwrap(), 4);
+
+        // half of priority 0 is down: 70% to priority 0, 30% to priority 1.
+        balancer.set_down(&1, false).unwrap();
+        balancer.set_down(&3, false).unwrap();
+        balancer.set_overprovisioning_factor(Some(1.4));
+        let mut counts = [0; 5];
+        for _ in 0..100 {
+            counts[*balancer.next_id().unwrap() as usize] += 1;
+        }
+        assert_eq!(counts, [0, 70, 0, 30, 0]);
+
+        balancer.set_overprovisioning_factor(None);
+        assert_eq!(*balancer.next_id().unwrap(), 1);
+        assert_eq!(*balancer.next_id().unwrap(), 1);
+    }
+
+    #[test]
+    fn panic() {
+        let nodes = (1..=10).map(Node::new_with_default_weight).collect();
+        let mut balancer = RoundRobin::new(nodes);
+        balancer.set_panic_threshold(Some(0.5));
+        for id in 1..=8 {
+            balancer.set_down(&id, true).unwrap();
+        }
+        // 20% healthy: all nodes in turn.
+        let sequence: Vec<i32> = (0..10).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, (1..=10).collect::<Vec<i32>>());
+
+        // 50% healthy: live nodes only.
+        for id in 1..=3 {
+            balancer.set_down(&id, false).unwrap();
+        }
+        let sequence: Vec<i32> = (0..5).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![1, 2, 3, 9, 10]);
+
+        for id in 1..=10 {
+            balancer.set_down(&id, true).unwrap();
+        }
+        assert_eq!(*balancer.next_id().unwrap(), 1);
+        balancer.set_panic_threshold(None);
+        assert!(balancer.next_id().is_none());
+    }
+
+    #[test]
+    fn max_conns() {
+        let mut balancer = RoundRobin::new(vec![
+            Node::new_with_default_weight(1).with_max_conns(1),
+            Node::new_with_default_weight(2).with_max_conns(2),
+        ]);
+        let first = balancer.acquire().unwrap();
+        let second = balancer.acquire().unwrap();
+        let third = balancer.acquire().unwrap();
+        assert_eq!((*first.get_id(), *second.get_id(), *third.get_id()), (1, 2, 2));
+        assert_eq!(balancer.get_node(&2).unwrap().get_conns(), 2);
+        assert!(balancer.next_id().is_none());
+        assert_eq!(balancer.acquire().err(), Some(AcquireError::Saturated));
+
+        drop(first);
+        assert_eq!(*balancer.acquire().unwrap().get_id(), 1);
+        assert_eq!(balancer.get_node(&1).unwrap().get_conns(), 0);
+
+        drop(second);
+        drop(third);
+        balancer.set_down(&1, true).unwrap();
+        balancer.set_down(&2, true).unwrap();
+        assert_eq!(balancer.acquire().err(), Some(AcquireError::Unavailable));
+
+        // a saturated node that is down does not count.
+        balancer.set_down(&1, false).unwrap();
+        let guard = balancer.acquire().unwrap();
+        assert_eq!(*guard.get_id(), 1);
+        balancer.set_down(&1, true).unwrap();
+        assert_eq!(balancer.acquire().err(), Some(AcquireError::Unavailable));
+    }
+
+    /// routes tenants to nodes with the same parity.
+    struct Tenant(Request);
+
+    impl RequestContext<i32> for Tenant {
+        fn accepts(&self, node: &Node<i32>) -> bool {
+            let tenant: i32 = self.0.get_header("X-Tenant").unwrap().parse().unwrap();
+            node.get_id() % 2 == tenant % 2
+        }
+    }
+
+    #[test]
+    fn next_for() {
+        let nodes = (1..=5).map(Node::new_with_default_weight).collect();
+        let mut balancer = RoundRobin::new(nodes);
+        let even = Tenant(Request::new().with_header("x-tenant", "8"));
+        let sequence: Vec<i32> = (0..4).map(|_| balancer.next_for(&even).unwrap().id).collect();
+        assert_eq!(sequence, vec![2, 4, 2, 4]);
+        assert_eq!(balancer.next_for(&Request::new()).unwrap().id, 5);
+
+        balancer.set_down(&2, true).unwrap();
+        balancer.set_down(&4, true).unwrap();
+        assert!(balancer.next_for(&even).is_none());
+    }
+
+    #[test]
+    fn next_excluding() {
+        let nodes = (1..=4).map(Node::new_with_default_weight).collect();
+        let mut balancer = RoundRobin::new(nodes);
+        let mut tried = vec![balancer.next().unwrap().id];
+        while let Some(node) = balancer.next_excluding(&tried) {
+            tried.push(node.id);
+        }
+        assert_eq!(tried, vec![1, 2, 3, 4]);
+
+        balancer.set_down(&2, true).unwrap();
+        assert_eq!(balancer.next_excluding(&[1]).unwrap().id, 3);
+    }
+
+    #[test]
+    fn next_hedged() {
+        let nodes = (1..=4).map(Node::new_with_default_weight).collect();
+        let mut balancer = RoundRobin::new(nodes);
+        // odd and even nodes in two zones.
+        let same_zone = |a: &Node<i32>, b: &Node<i32>| a.id % 2 == b.id % 2;
+        let ids = |nodes: Vec<&Node<i32>>| nodes.into_iter().map(|node| node.id).collect::<Vec<i32>>();
+        assert_eq!(ids(balancer.next_hedged(2, Some(&same_zone))), vec![1, 2]);
+        assert_eq!(ids(balancer.next_hedged(2, Some(&same_zone))), vec![3, 4]);
+        assert_eq!(ids(balancer.next_hedged(3, None)), vec![1, 2, 3]);
+
+        // a single zone left.
+        balancer.set_down(&2, true).unwrap();
+        balancer.set_down(&4, true).unwrap();
+        assert_eq!(ids(balancer.next_hedged(3, Some(&same_zone))), vec![1, 3]);
+    }
+
+    #[test]
+    fn next_matching() {
+        let mut balancer = RoundRobin::new(vec![
+            Node::new(1, 1).with_label("version", "v1"),
+            Node::new(2, 1).with_label("version", "v1"),
+            Node::new(3, 1).with_label("version", "v2"),
+            Node::new(4, 1).with_label("version", "v2"),
+        ]);
+        let v2 = Selector::parse("version=v2").unwrap();
+        let mut sequence = Vec::new();
+        let mut matching = Vec::new();
+        for _ in 0..4 {
+            sequence.push(*balancer.next_id().unwrap());
+            matching.push(*balancer.next_matching(&v2).unwrap().get_id());
+        }
+        // filtered selections keep their own position, next() still goes over every node.
+        assert_eq!(sequence, vec![1, 2, 3, 4]);
+        assert_eq!(matching, vec![3, 4, 3, 4]);
+    }
 }
@@ -0,0 +1,293 @@
+use std::collections::BTreeMap;
+use std::hash::Hash;
+
+use crate::{Balancer, Node, RequestContext};
+use crate::errors::{DuplicatedKeyError, NotFoundError};
+use crate::kinetic_tree::KineticTree;
+use crate::nodes::NodesContainer;
+use crate::selection::{self, Filter, Priorities, Select};
+
+/// Smooth weighted round robin with the same sequence as `WeightedRoundRobin`,
+/// but `next()` is amortized O(log² n) instead of a scan over all nodes.
+/// add/remove/set_down are O(n), so is `next()` in panic or when some node has max_conns
+/// or a rate limit.
+pub struct ScalableWeightedRoundRobin<T: Hash + Eq + Clone> {
+    nodes: NodesContainer<T>,
+    priorities: Priorities,
+    /// by priority.
+    levels: BTreeMap<usize, LevelTree>,
+    /// some node has max_conns or a rate limit, such nodes must be frozen when skipped.
+    limited: bool,
+}
+
+/// current weights of the nodes of a priority level.
+struct LevelTree {
+    tree: KineticTree,
+    /// indexes in `nodes`, ascending.
+    indexes: Vec<usize>,
+    /// sum of effective weights of live nodes.
+    total: i128,
+}
+
+impl<T: Hash + Eq + Clone> ScalableWeightedRoundRobin<T> {
+    pub fn new(nodes: Vec<Node<T>>) -> ScalableWeightedRoundRobin<T> {
+        let current_weights = nodes.iter().map(|node| node.current_weight as i128).collect();
+        let mut balancer = ScalableWeightedRoundRobin {
+            nodes: NodesContainer::from(nodes),
+            priorities: Priorities::new(),
+            levels: BTreeMap::new(),
+            limited: false,
+        };
+        balancer.rebuild(current_weights);
+        balancer
+    }
+
+    /// indexed like `nodes`.
+    fn current_weights(&self) -> Vec<i128> {
+        let mut current_weights = vec![0; self.nodes.len()];
+        for level in self.levels.values() {
+            for (position, index) in level.indexes.iter().enumerate() {
+                current_weights[*index] = level.tree.value(position);
+            }
+        }
+        current_weights
+    }
+
+    /// O(n)
+    fn rebuild(&mut self, current_weights: Vec<i128>) {
+        // by priority: indexes in `nodes` and lines of the tree.
+        let mut levels: BTreeMap<usize, (Vec<usize>, Vec<_>)> = BTreeMap::new();
+        self.limited = false;
+        for (index, node) in self.nodes.get_all().into_iter().enumerate() {
+            self.limited |= node.get_max_conns() > 0 || node.has_rate_limit();
+            let (indexes, lines) = levels.entry(node.priority).or_default();
+            indexes.push(index);
+            lines.push((
+                current_weights[index],
+                node.effective_weight as i128,
+                !node.is_down(),
+            ));
+        }
+        self.levels = levels
+            .into_iter()
+            .map(|(priority, (indexes, lines))| {
+                let total = lines
+                    .iter()
+                    .filter(|(_, _, active)| *active)
+                    .map(|(_, weight, _)| weight)
+                    .sum();
+                let level = LevelTree {
+                    tree: KineticTree::new(lines),
+                    indexes,
+                    total,
+                };
+                (priority, level)
+            })
+            .collect();
+    }
+}
+
+impl<T: Hash + Eq + Clone> Balancer<T> for ScalableWeightedRoundRobin<T> {
+    fn add_node(&mut self, node: Node<T>) -> Result<(), DuplicatedKeyError> {
+        let mut current_weights = self.current_weights();
+        let current_weight = node.current_weight as i128;
+        self.nodes.insert(node)?;
+        current_weights.push(current_weight);
+        self.rebuild(current_weights);
+        Ok(())
+    }
+
+    fn remove_node(&mut self, id: &T) -> Result<(), NotFoundError> {
+        let mut current_weights = self.current_weights();
+        let index = self.nodes.remove(id)?;
+        current_weights.remove(index);
+        self.rebuild(current_weights);
+        Ok(())
+    }
+
+    fn contains_id(&mut self, id: &T) -> bool {
+        self.nodes.get_by_id(id).is_some()
+    }
+
+    fn get_node(&self, id: &T) -> Option<&Node<T>> {
+        self.nodes.get_by_id(id)
+    }
+
+    fn get_nodes(&self) -> Vec<&Node<T>> {
+        self.nodes.get_all()
+    }
+
+    fn set_down(&mut self, id: &T, down: bool) -> Result<(), NotFoundError> {
+        self.nodes.set_down(id, down)?;
+        let index = self.nodes.index_of(id).ok_or(NotFoundError)?;
+        let node = self.nodes.get_by_index(index).ok_or(NotFoundError)?;
+        let weight = node.effective_weight as i128;
+        let level = self.levels.get_mut(&node.priority).ok_or(NotFoundError)?;
+        let position = level.indexes.binary_search(&index).map_err(|_| NotFoundError)?;
+        if level.tree.is_active(position) == down {
+            level.tree.set_active(position, !down);
+            level.total += if down { -weight } else { weight };
+        }
+        Ok(())
+    }
+
+    fn set_overprovisioning_factor(&mut self, factor: Option<f64>) {
+        self.priorities.set_overprovisioning_factor(factor);
+    }
+
+    fn set_panic_threshold(&mut self, threshold: Option<f64>) {
+        self.priorities.set_panic_threshold(threshold);
+    }
+
+    fn next_id(&mut self) -> Option<&T> {
+        self.next().map(|n| &n.id)
+    }
+
+    fn next(&mut self) -> Option<&Node<T>> {
+        let index = selection::next(self, None)?;
+        self.nodes.get_by_index(index)
+    }
+
+    fn next_for(&mut self, ctx: &dyn RequestContext<T>) -> Option<&Node<T>> {
+        let index = selection::next(self, Some(&|node| ctx.accepts(node)))?;
+        self.nodes.get_by_index(index)
+    }
+}
+
+impl<T: Hash + Eq + Clone> Select<T> for ScalableWeightedRoundRobin<T> {
+    fn state(&mut self) -> (&NodesContainer<T>, &mut Priorities) {
+        (&self.nodes, &mut self.priorities)
+    }
+
+    /// live nodes not accepted (filtered out, saturated, rate limited) are frozen for this step.
+    /// in panic, the down nodes accepted are woken up for this step. O(n)
+    fn select_level(&mut self, priority: usize, panic: bool, filter: Option<Filter<T>>) -> Option<usize> {
+        let level = self.levels.get_mut(&priority)?;
+        let mut frozen = Vec::new();
+        let mut woken = Vec::new();
+        let mut total = level.total;
+        if filter.is_some() || panic || self.limited {
+            for (position, index) in level.indexes.iter().enumerate() {
+                let node = match self.nodes.get_by_index(*index) {
+                    Some(node) => node,
+                    None => continue,
+                };
+                let accepted = selection::accepts(node, priority, panic, filter);
+                if !node.is_down() && !accepted {
+                    frozen.push(position);
+                    total -= node.effective_weight as i128;
+                } else if node.is_down() && accepted {
+                    woken.push(position);
+                    total += node.effective_weight as i128;
+                }
+            }
+        }
+        for position in &frozen {
+            level.tree.set_active(*position, false);
+        }
+        for position in &woken {
+            level.tree.set_active(*position, true);
+        }
+        let result = level.tree.advance();
+        if let Some(position) = result {
+            level.tree.add(position, -total);
+        }
+        for position in &frozen {
+            level.tree.set_active(*position, true);
+        }
+        for position in &woken {
+            level.tree.set_active(*position, false);
+        }
+        result.map(|position| level.indexes[position])
+    }
+}
+
+#[cfg(test)]
+mod scalable_weighted_round_robin_test {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::{Balancer, Node};
+    use crate::scalable_weighted_round_robin::ScalableWeightedRoundRobin;
+    use crate::weighted_round_robin::WeightedRoundRobin;
+
+    fn map_nodes(array: Vec<(i32, usize)>) -> Vec<Node<i32>> {
+        array.into_iter()
+            .map(|(id, weight)| Node::new(id, weight))
+            .collect()
+    }
+
+    #[test]
+    fn simple() {
+        let mut balancer = ScalableWeightedRoundRobin::new(map_nodes(vec![(1, 5), (2, 1), (3, 1)]));
+        let sequence: Vec<i32> = (0..14).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![1, 1, 2, 1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1]);
+    }
+
+    #[test]
+    fn down() {
+        let mut balancer = ScalableWeightedRoundRobin::new(map_nodes(vec![(1, 1), (2, 1), (3, 1)]));
+        balancer.set_down(&1, true).unwrap();
+        assert_ne!(*balancer.next_id().unwrap(), 1);
+        assert_ne!(*balancer.next_id().unwrap(), 1);
+
+        balancer.set_down(&2, true).unwrap();
+        balancer.set_down(&3, true).unwrap();
+        assert!(balancer.next_id().is_none());
+        assert!(balancer.set_down(&4, true).is_err());
+
+        balancer.remove_node(&2).unwrap();
+        balancer.remove_node(&3).unwrap();
+        balancer.remove_node(&1).unwrap();
+        assert!(balancer.next_id().is_none());
+    }
+
+    #[test]
+    fn max_conns() {
+        let mut balancer = ScalableWeightedRoundRobin::new(vec![
+            Node::new(1, 5).with_max_conns(1),
+            Node::new(2, 1),
+        ]);
+        let guard = balancer.acquire().unwrap();
+        assert_eq!(*guard.get_id(), 1);
+        let sequence: Vec<i32> = (0..3).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![2, 2, 2]);
+        drop(guard);
+        assert_eq!(*balancer.next_id().unwrap(), 1);
+    }
+
+    #[test]
+    fn same_as_weighted_round_robin() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let nodes: Vec<Node<i32>> = (0..50)
+            .map(|id| Node::new(id, rng.gen_range(0..10)).with_priority(rng.gen_range(0..3)))
+            .collect();
+        let mut expected = WeightedRoundRobin::new(nodes.clone());
+        let mut balancer = ScalableWeightedRoundRobin::new(nodes);
+        expected.set_overprovisioning_factor(Some(1.4));
+        balancer.set_overprovisioning_factor(Some(1.4));
+        expected.set_panic_threshold(Some(0.3));
+        balancer.set_panic_threshold(Some(0.3));
+        let mut next_id = 50;
+        for _ in 0..20000 {
+            match rng.gen_range(0..100) {
+                0 => {
+                    let node = Node::new(next_id, rng.gen_range(0..10)).with_priority(rng.gen_range(0..3));
+                    expected.add_node(node.clone()).unwrap();
+                    balancer.add_node(node).unwrap();
+                    next_id += 1;
+                }
+                1 => {
+                    let id = rng.gen_range(0..next_id);
+                    assert_eq!(expected.remove_node(&id).is_ok(), balancer.remove_node(&id).is_ok());
+                }
+                2..=6 => {
+                    let id = rng.gen_range(0..next_id);
+                    let down = rng.gen_bool(0.5);
+                    assert_eq!(expected.set_down(&id, down).is_ok(), balancer.set_down(&id, down).is_ok());
+                }
+                _ => assert_eq!(expected.next_id(), balancer.next_id()),
+            }
+        }
+    }
+}
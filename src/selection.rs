@@ -0,0 +1,313 @@
+use std::collections::BTreeMap;
+use std::hash::Hash;
+
+use crate::Node;
+use crate::nodes::{Level, NodesContainer};
+
+/// accepts or rejects a node for one selection.
+pub type Filter<'a, T> = &'a dyn Fn(&Node<T>) -> bool;
+
+/// Selection step of a strategy, restricted to one priority level.
+pub trait Select<T: Hash + Eq + Clone> {
+    fn state(&mut self) -> (&NodesContainer<T>, &mut Priorities);
+
+    /// index of the selected node of the priority level that is accepted by the filter,
+    /// none if there is no such node. down nodes are only selected in panic,
+    /// saturated or rate limited nodes are never selected.
+    /// the strategy state only moves forward when a node is selected.
+    fn select_level(&mut self, priority: usize, panic: bool, filter: Option<Filter<T>>) -> Option<usize>;
+}
+
+pub fn accepts<T: Hash + Eq + Clone>(
+    node: &Node<T>,
+    priority: usize,
+    panic: bool,
+    filter: Option<Filter<T>>,
+) -> bool {
+    (panic || !node.is_down())
+        && node.priority == priority
+        && !node.is_saturated()
+        && !node.is_rate_limited()
+        && filter.is_none_or(|filter| filter(node))
+}
+
+/// chooses a priority level, then a node of that level accepted by the filter,
+/// and takes a token of the node.
+pub fn next<T: Hash + Eq + Clone, S: Select<T>>(balancer: &mut S, filter: Option<Filter<T>>) -> Option<usize> {
+    let index = choose(balancer, filter)?;
+    let (nodes, _) = balancer.state();
+    nodes.get_by_index(index)?.take_token();
+    Some(index)
+}
+
+fn choose<T: Hash + Eq + Clone, S: Select<T>>(balancer: &mut S, filter: Option<Filter<T>>) -> Option<usize> {
+    let (nodes, priorities) = balancer.state();
+    let (priority, panic) = priorities.choose(nodes.levels())?;
+    if let Some(index) = balancer.select_level(priority, panic, filter) {
+        return Some(index);
+    }
+    // nothing selectable in the chosen level (e.g. zero weights, filtered out), try the others in order.
+    let (nodes, priorities) = balancer.state();
+    let others: Vec<(usize, bool)> = nodes
+        .levels()
+        .iter()
+        .filter(|(other, level)| **other != priority && level.healthy > 0)
+        .map(|(other, level)| (*other, priorities.in_panic(nodes.levels(), level)))
+        .collect();
+    others
+        .into_iter()
+        .find_map(|(other, panic)| balancer.select_level(other, panic, filter))
+}
+
+/// 100% in basis points.
+pub(crate) const FULL_LOAD: i64 = 10000;
+
+/// key chosen by smooth weighted round robin (like nginx) over the loads, the first on ties.
+/// `current_weights` keeps the state of each key between calls. O(number of loads)
+pub(crate) fn smooth_choose<K: Ord + Clone>(
+    current_weights: &mut BTreeMap<K, i64>,
+    loads: &[(K, i64)],
+) -> Option<K> {
+    let mut total = 0;
+    let mut result: Option<(&K, i64)> = None;
+    for (key, load) in loads {
+        let current_weight = current_weights.entry(key.clone()).or_insert(0);
+        *current_weight += load;
+        total += load;
+        if result.is_none_or(|(_, best)| best < *current_weight) {
+            result = Some((key, *current_weight));
+        }
+    }
+    let key = result?.0.clone();
+    *current_weights.entry(key.clone()).or_insert(0) -= total;
+    Some(key)
+}
+
+/// Splits traffic between priority levels.
+///
+/// Without overprovisioning factor, all traffic goes to the first level with a live node.
+/// With a factor (like Envoy), the health of a level is
+/// `min(100%, factor * healthy nodes / nodes)` and each level takes as much of the remaining
+/// traffic as its health, so traffic spills gradually to the next level.
+///
+/// Like Envoy, panic is only considered when the levels together are not healthy enough,
+/// i.e. the sum of their health (with a factor of 1 without overprovisioning factor) is below
+/// 100%. Then a level whose healthy fraction is below the panic threshold is in panic:
+/// its nodes are selected regardless of their down flag.
+pub struct Priorities {
+    overprovisioning_factor: Option<f64>,
+    panic_threshold: Option<f64>,
+    /// smooth weighted round robin between levels.
+    current_weights: BTreeMap<usize, i64>,
+}
+
+impl Priorities {
+    pub fn new() -> Priorities {
+        Priorities {
+            overprovisioning_factor: None,
+            panic_threshold: None,
+            current_weights: BTreeMap::new(),
+        }
+    }
+
+    /// a factor that is not a positive number is the same as None.
+    pub fn set_overprovisioning_factor(&mut self, factor: Option<f64>) {
+        self.overprovisioning_factor = factor.filter(|factor| factor.is_finite() && *factor > 0.0);
+    }
+
+    pub fn set_panic_threshold(&mut self, threshold: Option<f64>) {
+        self.panic_threshold = threshold;
+    }
+
+    /// `level` is one of `levels`.
+    pub fn in_panic(&self, levels: &BTreeMap<usize, Level>, level: &Level) -> bool {
+        self.panic_threshold
+            .is_some_and(|threshold| (level.healthy as f64) < threshold * level.total as f64)
+            && self.total_health(levels) < FULL_LOAD
+    }
+
+    /// health of a level in basis points, at most 100%.
+    fn health(factor: f64, level: &Level) -> i64 {
+        let health = factor * level.healthy as f64 / level.total as f64;
+        ((health * FULL_LOAD as f64).round() as i64).min(FULL_LOAD)
+    }
+
+    /// sum of the health of the levels in basis points, at most 100%.
+    fn total_health(&self, levels: &BTreeMap<usize, Level>) -> i64 {
+        let factor = self.overprovisioning_factor.unwrap_or(1.0);
+        levels
+            .values()
+            .map(|level| Self::health(factor, level))
+            .sum::<i64>()
+            .min(FULL_LOAD)
+    }
+
+    /// share of traffic of each level in basis points, empty if no node is live.
+    pub fn loads(&self, levels: &BTreeMap<usize, Level>) -> Vec<(usize, i64)> {
+        let first_live = || {
+            levels
+                .iter()
+                .find(|(_, level)| level.healthy > 0)
+                .map(|(priority, _)| vec![(*priority, FULL_LOAD)])
+                .unwrap_or_default()
+        };
+        let factor = match self.overprovisioning_factor {
+            Some(factor) => factor,
+            None => return first_live(),
+        };
+        let health: Vec<(usize, i64)> = levels
+            .iter()
+            .map(|(priority, level)| (*priority, Self::health(factor, level)))
+            .filter(|(_, health)| *health > 0)
+            .collect();
+        let total: i64 = health.iter().map(|(_, health)| health).sum();
+        if total == 0 {
+            // every health rounds to 0 with a small factor.
+            return first_live();
+        }
+        if total < FULL_LOAD {
+            // not enough healthy capacity in all levels together, scale up.
+            return health
+                .into_iter()
+                .map(|(priority, health)| (priority, health * FULL_LOAD / total))
+                .collect();
+        }
+        let mut remaining = FULL_LOAD;
+        health
+            .into_iter()
+            .map(|(priority, health)| {
+                let load = health.min(remaining);
+                remaining -= load;
+                (priority, load)
+            })
+            .filter(|(_, load)| *load > 0)
+            .collect()
+    }
+
+    /// priority level of a request and whether it is in panic. O(number of levels)
+    /// without any live node, the first level is used in panic if there is a panic threshold.
+    pub fn choose(&mut self, levels: &BTreeMap<usize, Level>) -> Option<(usize, bool)> {
+        let loads = self.loads(levels);
+        let priority = match loads[..] {
+            [] => {
+                return levels
+                    .iter()
+                    .next()
+                    .filter(|(_, level)| self.in_panic(levels, level))
+                    .map(|(priority, _)| (*priority, true));
+            }
+            [(priority, _)] => priority,
+            _ => smooth_choose(&mut self.current_weights, &loads)?,
+        };
+        Some((priority, levels.get(&priority).is_some_and(|level| self.in_panic(levels, level))))
+    }
+}
+
+#[cfg(test)]
+mod selection_test {
+    use std::collections::BTreeMap;
+
+    use crate::nodes::Level;
+    use crate::selection::{smooth_choose, Priorities};
+
+    fn levels(array: Vec<(usize, usize, usize)>) -> BTreeMap<usize, Level> {
+        array.into_iter()
+            .map(|(priority, total, healthy)| (priority, Level { total, healthy }))
+            .collect()
+    }
+
+    #[test]
+    fn strict() {
+        let priorities = Priorities::new();
+        assert_eq!(priorities.loads(&levels(vec![(0, 10, 1), (1, 10, 10)])), vec![(0, 10000)]);
+        assert_eq!(priorities.loads(&levels(vec![(0, 10, 0), (2, 10, 10)])), vec![(2, 10000)]);
+        assert!(priorities.loads(&levels(vec![(0, 10, 0)])).is_empty());
+        assert!(priorities.loads(&levels(vec![])).is_empty());
+    }
+
+    #[test]
+    fn overprovisioning() {
+        let mut priorities = Priorities::new();
+        priorities.set_overprovisioning_factor(Some(1.4));
+        assert_eq!(priorities.loads(&levels(vec![(0, 100, 100), (1, 10, 10)])), vec![(0, 10000)]);
+        assert_eq!(priorities.loads(&levels(vec![(0, 100, 72), (1, 10, 10)])), vec![(0, 10000)]);
+        assert_eq!(
+            priorities.loads(&levels(vec![(0, 100, 50), (1, 10, 10)])),
+            vec![(0, 7000), (1, 3000)]
+        );
+        assert_eq!(
+            priorities.loads(&levels(vec![(0, 100, 50), (1, 10, 1), (2, 10, 10)])),
+            vec![(0, 7000), (1, 1400), (2, 1600)]
+        );
+        // normalized when all levels together are not healthy enough.
+        assert_eq!(
+            priorities.loads(&levels(vec![(0, 10, 2), (1, 10, 2)])),
+            vec![(0, 5000), (1, 5000)]
+        );
+        assert!(priorities.loads(&levels(vec![(0, 10, 0), (1, 10, 0)])).is_empty());
+    }
+
+    #[test]
+    fn invalid_factor() {
+        let mut priorities = Priorities::new();
+        for factor in [0.0, -1.4, f64::NAN, f64::INFINITY] {
+            priorities.set_overprovisioning_factor(Some(factor));
+            assert_eq!(priorities.loads(&levels(vec![(0, 10, 1), (1, 10, 10)])), vec![(0, 10000)]);
+        }
+
+        // health rounds to 0 in every level.
+        priorities.set_overprovisioning_factor(Some(0.00001));
+        assert_eq!(priorities.loads(&levels(vec![(0, 10, 0), (1, 10, 1), (2, 10, 10)])), vec![(1, 10000)]);
+        assert_eq!(priorities.choose(&levels(vec![(0, 10, 0), (1, 10, 1)])), Some((1, false)));
+    }
+
+    #[test]
+    fn smooth() {
+        let mut current_weights = BTreeMap::new();
+        let loads = [("a", 5), ("b", 1), ("c", 1)];
+        let sequence: Vec<&str> = (0..7).map(|_| smooth_choose(&mut current_weights, &loads).unwrap()).collect();
+        assert_eq!(sequence, vec!["a", "a", "b", "a", "c", "a", "a"]);
+        assert!(current_weights.values().all(|weight| *weight == 0));
+        assert_eq!(smooth_choose(&mut current_weights, &[]), None::<&str>);
+    }
+
+    #[test]
+    fn choose() {
+        let mut priorities = Priorities::new();
+        priorities.set_overprovisioning_factor(Some(1.4));
+        let levels = levels(vec![(0, 100, 50), (1, 10, 10)]);
+        let mut counts = [0, 0];
+        for _ in 0..100 {
+            counts[priorities.choose(&levels).unwrap().0] += 1;
+        }
+        assert_eq!(counts, [70, 30]);
+    }
+
+    #[test]
+    fn panic() {
+        let mut priorities = Priorities::new();
+        assert_eq!(priorities.choose(&levels(vec![(0, 10, 1), (1, 10, 10)])), Some((0, false)));
+        assert_eq!(priorities.choose(&levels(vec![(0, 10, 0), (1, 10, 0)])), None);
+
+        priorities.set_panic_threshold(Some(0.5));
+        assert_eq!(priorities.choose(&levels(vec![(0, 10, 5), (1, 10, 10)])), Some((0, false)));
+        // enough healthy nodes in the levels together, no panic.
+        assert_eq!(priorities.choose(&levels(vec![(0, 10, 1), (1, 10, 10)])), Some((0, false)));
+        assert_eq!(priorities.choose(&levels(vec![(0, 10, 1), (1, 10, 2)])), Some((0, true)));
+        assert_eq!(priorities.choose(&levels(vec![(0, 10, 1)])), Some((0, true)));
+        assert_eq!(priorities.choose(&levels(vec![(0, 10, 0), (1, 10, 1)])), Some((1, true)));
+        assert_eq!(priorities.choose(&levels(vec![(0, 10, 0), (1, 10, 0)])), Some((0, true)));
+        assert_eq!(priorities.choose(&levels(vec![])), None);
+
+        // the backups take the traffic the first level cannot.
+        priorities.set_overprovisioning_factor(Some(1.4));
+        let levels = levels(vec![(0, 10, 1), (1, 10, 10)]);
+        let mut counts = [0, 0];
+        for _ in 0..100 {
+            let (priority, panic) = priorities.choose(&levels).unwrap();
+            assert!(!panic);
+            counts[priority] += 1;
+        }
+        assert_eq!(counts, [14, 86]);
+    }
+}
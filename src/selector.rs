@@ -0,0 +1,117 @@
+use std::fmt;
+use std::hash::Hash;
+use std::str::FromStr;
+
+use crate::errors::SelectorParseError;
+use crate::{Node, RequestContext};
+
+/// Label selector like Kubernetes equality based selectors: `version=v2, zone!=us-east-1a`.
+/// a node matches when it matches every requirement, the empty selector matches every node.
+/// `key!=value` also matches nodes without the label.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Selector {
+    requirements: Vec<Requirement>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Requirement {
+    key: String,
+    value: String,
+    equal: bool,
+}
+
+impl Selector {
+    /// requirements separated by `,`, each `key=value`, `key==value` or `key!=value`.
+    pub fn parse(selector: &str) -> Result<Selector, SelectorParseError> {
+        if selector.trim().is_empty() {
+            return Ok(Selector::default());
+        }
+        let requirements = selector
+            .split(',')
+            .map(|requirement| {
+                let (key, value, equal) = if let Some((key, value)) = requirement.split_once("!=") {
+                    (key, value, false)
+                } else if let Some((key, value)) = requirement.split_once("==") {
+                    (key, value, true)
+                } else if let Some((key, value)) = requirement.split_once('=') {
+                    (key, value, true)
+                } else {
+                    return Err(SelectorParseError(requirement.trim().to_string()));
+                };
+                let (key, value) = (key.trim(), value.trim());
+                if key.is_empty() || value.contains(['=', '!']) {
+                    return Err(SelectorParseError(requirement.trim().to_string()));
+                }
+                Ok(Requirement {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    equal,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Selector { requirements })
+    }
+
+    pub fn matches<T: Hash + Eq + Clone>(&self, node: &Node<T>) -> bool {
+        self.requirements.iter().all(|requirement| {
+            (node.get_label(&requirement.key) == Some(requirement.value.as_str())) == requirement.equal
+        })
+    }
+}
+
+impl FromStr for Selector {
+    type Err = SelectorParseError;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        Selector::parse(selector)
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, requirement) in self.requirements.iter().enumerate() {
+            if index > 0 {
+                write!(f, ",")?;
+            }
+            let op = if requirement.equal { "=" } else { "!=" };
+            write!(f, "{}{}{}", requirement.key, op, requirement.value)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Hash + Eq + Clone> RequestContext<T> for Selector {
+    fn accepts(&self, node: &Node<T>) -> bool {
+        self.matches(node)
+    }
+}
+
+#[cfg(test)]
+mod selector_test {
+    use crate::Node;
+    use crate::selector::Selector;
+
+    #[test]
+    fn parse() {
+        let selector = Selector::parse(" version = v2,zone!=us-east-1a, canary==true").unwrap();
+        assert_eq!(selector.to_string(), "version=v2,zone!=us-east-1a,canary=true");
+        assert_eq!(selector, selector.to_string().parse().unwrap());
+        assert_eq!(Selector::parse("").unwrap(), Selector::default());
+
+        for invalid in ["version", "=v2", "version=v2,", "a=b=c", "a!=b=c", "a=!b"] {
+            assert!(Selector::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn matches() {
+        let node = Node::new(1, 1).with_label("version", "v2").with_zone("us-east-1b");
+        assert!(Selector::parse("version=v2").unwrap().matches(&node));
+        assert!(Selector::parse("version=v2, zone!=us-east-1a").unwrap().matches(&node));
+        assert!(Selector::parse("canary!=true").unwrap().matches(&node));
+        assert!(Selector::parse("").unwrap().matches(&node));
+        assert!(!Selector::parse("version=v1").unwrap().matches(&node));
+        assert!(!Selector::parse("version=v2, zone!=us-east-1b").unwrap().matches(&node));
+        assert!(!Selector::parse("canary=true").unwrap().matches(&node));
+    }
+}
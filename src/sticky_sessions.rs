@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::errors::{DuplicatedKeyError, NotFoundError};
+use crate::{Balancer, Clock, Node, RequestContext, SystemClock};
+
+/// Session affinity over another balancer.
+///
+/// `next_for` remembers the node of each session (`RequestContext::hash_key`) and selects it
+/// again until the session is idle for longer than the ttl. New and expired sessions, and
+/// sessions whose node was removed, is down or is not accepted by the request, get a node from
+/// the inner balancer. While the node of a session is saturated or rate limited, its requests
+/// go to another node without moving the session.
+/// At most `capacity` sessions are kept, the least recently used is forgotten first.
+pub struct StickySessions<T: Hash + Eq + Clone, B: Balancer<T>> {
+    inner: B,
+    ttl: Duration,
+    capacity: usize,
+    clock: Arc<dyn Clock>,
+    sessions: HashMap<String, Session<T>>,
+    /// session by last use, oldest first.
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+struct Session<T> {
+    id: T,
+    expires: Instant,
+    /// key in `lru`.
+    tick: u64,
+}
+
+impl<T: Hash + Eq + Clone, B: Balancer<T>> StickySessions<T, B> {
+    pub fn new(inner: B, ttl: Duration, capacity: usize) -> StickySessions<T, B> {
+        StickySessions {
+            inner,
+            ttl,
+            capacity,
+            clock: Arc::new(SystemClock),
+            sessions: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> StickySessions<T, B> {
+        self.clock = clock;
+        self
+    }
+
+    pub fn get_inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// number of sessions, expired sessions included until they are used or evicted.
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// node of a live session.
+    pub fn get_session(&self, session: &str) -> Option<&T> {
+        self.sessions
+            .get(session)
+            .filter(|entry| entry.expires > self.clock.now())
+            .map(|entry| &entry.id)
+    }
+
+    pub fn forget(&mut self, session: &str) -> bool {
+        match self.sessions.remove(session) {
+            Some(entry) => {
+                self.lru.remove(&entry.tick);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// O(log n)
+    fn remember(&mut self, session: &str, id: T, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.forget(session);
+        while self.sessions.len() >= self.capacity {
+            match self.lru.pop_first() {
+                Some((_, oldest)) => self.sessions.remove(&oldest),
+                None => break,
+            };
+        }
+        self.tick += 1;
+        self.lru.insert(self.tick, session.to_string());
+        self.sessions.insert(session.to_string(), Session {
+            id,
+            expires: now + self.ttl,
+            tick: self.tick,
+        });
+    }
+
+    fn select(&mut self, ctx: &dyn RequestContext<T>) -> Option<T> {
+        let session = match ctx.hash_key() {
+            Some(session) => session.to_string(),
+            None => return self.inner.next_for(ctx).map(|node| node.id.clone()),
+        };
+        let now = self.clock.now();
+        let bound = self
+            .sessions
+            .get(&session)
+            .filter(|entry| entry.expires > now)
+            .and_then(|entry| self.inner.get_node(&entry.id))
+            .filter(|node| !node.is_down() && ctx.accepts(node));
+        match bound {
+            Some(node) if !node.is_saturated() && node.take_token() => {
+                let id = node.id.clone();
+                self.remember(&session, id.clone(), now);
+                Some(id)
+            }
+            // busy, keep the session.
+            Some(_) => self.inner.next_for(ctx).map(|node| node.id.clone()),
+            None => {
+                let id = self.inner.next_for(ctx)?.id.clone();
+                self.remember(&session, id.clone(), now);
+                Some(id)
+            }
+        }
+    }
+}
+
+impl<T: Hash + Eq + Clone, B: Balancer<T>> Balancer<T> for StickySessions<T, B> {
+    fn add_node(&mut self, node: Node<T>) -> Result<(), DuplicatedKeyError> {
+        self.inner.add_node(node)
+    }
+
+    /// the sessions of the node move on their next request.
+    fn remove_node(&mut self, id: &T) -> Result<(), NotFoundError> {
+        self.inner.remove_node(id)
+    }
+
+    fn contains_id(&mut self, id: &T) -> bool {
+        self.inner.contains_id(id)
+    }
+
+    fn get_node(&self, id: &T) -> Option<&Node<T>> {
+        self.inner.get_node(id)
+    }
+
+    fn get_nodes(&self) -> Vec<&Node<T>> {
+        self.inner.get_nodes()
+    }
+
+    fn set_down(&mut self, id: &T, down: bool) -> Result<(), NotFoundError> {
+        self.inner.set_down(id, down)
+    }
+
+    fn set_overprovisioning_factor(&mut self, factor: Option<f64>) {
+        self.inner.set_overprovisioning_factor(factor);
+    }
+
+    fn set_panic_threshold(&mut self, threshold: Option<f64>) {
+        self.inner.set_panic_threshold(threshold);
+    }
+
+    fn next_id(&mut self) -> Option<&T> {
+        self.next().map(|n| &n.id)
+    }
+
+    /// without session, use `next_for` or `acquire_for` with the session as hash key.
+    fn next(&mut self) -> Option<&Node<T>> {
+        self.inner.next()
+    }
+
+    fn next_for(&mut self, ctx: &dyn RequestContext<T>) -> Option<&Node<T>> {
+        let id = self.select(ctx)?;
+        self.inner.get_node(&id)
+    }
+}
+
+#[cfg(test)]
+mod sticky_sessions_test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::{AcquireError, Balancer, ManualClock, Node, Request};
+    use crate::round_robin::RoundRobin;
+    use crate::sticky_sessions::StickySessions;
+
+    fn balancer(clock: Arc<ManualClock>, capacity: usize) -> StickySessions<i32, RoundRobin<i32>> {
+        let nodes = (1..=3).map(Node::new_with_default_weight).collect();
+        StickySessions::new(RoundRobin::new(nodes), Duration::from_secs(60), capacity).with_clock(clock)
+    }
+
+    fn next(balancer: &mut StickySessions<i32, RoundRobin<i32>>, session: &str) -> i32 {
+        *balancer.next_for(&Request::new().with_hash_key(session)).unwrap().get_id()
+    }
+
+    #[test]
+    fn sticky() {
+        let clock = Arc::new(ManualClock::new());
+        let mut balancer = balancer(clock.clone(), 100);
+        assert_eq!(next(&mut balancer, "a"), 1);
+        assert_eq!(next(&mut balancer, "b"), 2);
+        assert_eq!(next(&mut balancer, "a"), 1);
+        // without session, the requests with session do not move its position.
+        assert_eq!(*balancer.next_id().unwrap(), 1);
+        assert_eq!(balancer.get_session("b"), Some(&2));
+
+        // the ttl is renewed by each request.
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(next(&mut balancer, "a"), 1);
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(next(&mut balancer, "a"), 1);
+        assert_eq!(balancer.get_session("b"), None);
+        assert_eq!(next(&mut balancer, "b"), 3);
+
+        balancer.set_down(&1, true).unwrap();
+        assert_eq!(next(&mut balancer, "a"), 2);
+        balancer.set_down(&1, false).unwrap();
+        assert_eq!(next(&mut balancer, "a"), 2);
+        balancer.remove_node(&2).unwrap();
+        assert_eq!(next(&mut balancer, "a"), 3);
+
+        assert!(balancer.forget("a"));
+        assert!(!balancer.forget("a"));
+        assert_eq!(balancer.len(), 1);
+    }
+
+    #[test]
+    fn lru() {
+        let clock = Arc::new(ManualClock::new());
+        let mut balancer = balancer(clock, 2);
+        assert_eq!(next(&mut balancer, "a"), 1);
+        assert_eq!(next(&mut balancer, "b"), 2);
+        assert_eq!(next(&mut balancer, "a"), 1);
+        // b is the least recently used.
+        assert_eq!(next(&mut balancer, "c"), 3);
+        assert_eq!(balancer.len(), 2);
+        assert_eq!(balancer.get_session("b"), None);
+        assert_eq!(balancer.get_session("a"), Some(&1));
+        assert_eq!(next(&mut balancer, "b"), 1);
+    }
+
+    #[test]
+    fn saturated() {
+        let nodes = vec![Node::new_with_default_weight(1).with_max_conns(1), Node::new_with_default_weight(2)];
+        let mut balancer = StickySessions::new(RoundRobin::new(nodes), Duration::from_secs(60), 10);
+        let request = Request::new().with_hash_key("a");
+        let guard = balancer.acquire().unwrap();
+        assert_eq!(*guard.get_id(), 1);
+        assert_eq!(*balancer.next_for(&request).unwrap().get_id(), 2);
+        drop(guard);
+
+        balancer.forget("a");
+        assert_eq!(*balancer.next_for(&request).unwrap().get_id(), 1);
+        assert_eq!(*balancer.acquire().unwrap().get_id(), 2);
+        let guard = balancer.acquire().unwrap();
+        assert_eq!(*guard.get_id(), 1);
+        // node 1 is busy, session a stays on it.
+        assert_eq!(*balancer.next_for(&request).unwrap().get_id(), 2);
+        assert_eq!(balancer.get_session("a"), Some(&1));
+    }
+
+    #[test]
+    fn acquire_for() {
+        let nodes = vec![Node::new_with_default_weight(1).with_max_conns(1), Node::new_with_default_weight(2)];
+        let mut balancer = StickySessions::new(RoundRobin::new(nodes), Duration::from_secs(60), 10);
+        let request = Request::new().with_hash_key("a");
+        let guard = balancer.acquire_for(&request).unwrap();
+        assert_eq!(*guard.get_id(), 1);
+        assert_eq!(balancer.get_session("a"), Some(&1));
+        // node 1 is busy, session a stays on it.
+        assert_eq!(*balancer.acquire_for(&request).unwrap().get_id(), 2);
+        drop(guard);
+        assert_eq!(*balancer.acquire_for(&request).unwrap().get_id(), 1);
+
+        balancer.set_down(&2, true).unwrap();
+        let guard = balancer.acquire_for(&request).unwrap();
+        assert_eq!(balancer.acquire_for(&request).err(), Some(AcquireError::Saturated));
+        drop(guard);
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+use std::hash::Hash;
+
+use crate::errors::{DuplicatedKeyError, NotFoundError};
+use crate::selection::{self, FULL_LOAD};
+use crate::{Balancer, BalancerEnum, Node, RequestContext, Selector};
+
+/// FNV-1a 64, the buckets must not change across processes and Rust versions.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Sends a percentage of the requests to the canary group, the rest to the stable group.
+///
+/// Nodes matching the canary selector are in the canary group, each group has its own balancer.
+/// Without sticky assignment, the split is exact over every 100 / gcd requests (smooth weighted
+/// round robin). With sticky assignment, `next_for` puts the request in a bucket by the hash of
+/// its key (hash key, else client address) with FNV-1a: the same key stays in the same group,
+/// also across processes, and raising the percentage only moves stable keys to the canary group.
+/// When a group has no node to select, the other group is used.
+pub struct TrafficSplit<'a, T: Hash + Eq + Clone> {
+    selector: Selector,
+    stable: Box<dyn Balancer<T> + 'a>,
+    canary: Box<dyn Balancer<T> + 'a>,
+    /// basis points.
+    canary_load: i64,
+    sticky: bool,
+    /// of the stable (false) and canary (true) groups.
+    current_weights: BTreeMap<bool, i64>,
+}
+
+impl<'a, T: Hash + Eq + Clone + 'a> TrafficSplit<'a, T> {
+    /// no traffic to the canary group until `set_canary_percent`.
+    pub fn new(
+        stable: BalancerEnum,
+        canary: BalancerEnum,
+        selector: Selector,
+        nodes: Vec<Node<T>>,
+    ) -> TrafficSplit<'a, T> {
+        let (canary_nodes, stable_nodes) = nodes.into_iter().partition(|node| selector.matches(node));
+        TrafficSplit {
+            selector,
+            stable: crate::new(stable, stable_nodes),
+            canary: crate::new(canary, canary_nodes),
+            canary_load: 0,
+            sticky: false,
+            current_weights: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_canary_percent(mut self, percent: f64) -> TrafficSplit<'a, T> {
+        self.set_canary_percent(percent);
+        self
+    }
+
+    pub fn with_sticky(mut self, sticky: bool) -> TrafficSplit<'a, T> {
+        self.sticky = sticky;
+        self
+    }
+
+    /// 0 to 100, with a precision of 0.01.
+    pub fn set_canary_percent(&mut self, percent: f64) {
+        self.canary_load = ((percent * 100.0).round() as i64).clamp(0, FULL_LOAD);
+    }
+
+    pub fn get_canary_percent(&self) -> f64 {
+        self.canary_load as f64 / 100.0
+    }
+
+    pub fn get_stable(&self) -> &dyn Balancer<T> {
+        self.stable.as_ref()
+    }
+
+    pub fn get_canary(&self) -> &dyn Balancer<T> {
+        self.canary.as_ref()
+    }
+
+    fn group_mut(&mut self, node: &Node<T>) -> &mut Box<dyn Balancer<T> + 'a> {
+        if self.selector.matches(node) {
+            &mut self.canary
+        } else {
+            &mut self.stable
+        }
+    }
+
+    fn group_of(&mut self, id: &T) -> Option<&mut Box<dyn Balancer<T> + 'a>> {
+        if self.stable.contains_id(id) {
+            Some(&mut self.stable)
+        } else if self.canary.contains_id(id) {
+            Some(&mut self.canary)
+        } else {
+            None
+        }
+    }
+
+    fn is_canary(&mut self, ctx: Option<&dyn RequestContext<T>>) -> bool {
+        let key = ctx.filter(|_| self.sticky).and_then(|ctx| match ctx.hash_key() {
+            Some(key) => Some(key.to_string()),
+            None => ctx.client_addr().map(|addr| addr.to_string()),
+        });
+        if let Some(key) = key {
+            return ((fnv1a(key.as_bytes()) % FULL_LOAD as u64) as i64) < self.canary_load;
+        }
+        // stable first on ties.
+        let loads = [(false, FULL_LOAD - self.canary_load), (true, self.canary_load)];
+        selection::smooth_choose(&mut self.current_weights, &loads).unwrap_or(false)
+    }
+
+    fn select(&mut self, ctx: Option<&dyn RequestContext<T>>) -> Option<T> {
+        let canary = self.is_canary(ctx);
+        let (first, second) = if canary {
+            (&mut self.canary, &mut self.stable)
+        } else {
+            (&mut self.stable, &mut self.canary)
+        };
+        for group in [first, second] {
+            let node = match ctx {
+                Some(ctx) => group.next_for(ctx),
+                None => group.next(),
+            };
+            if let Some(node) = node {
+                return Some(node.id.clone());
+            }
+        }
+        None
+    }
+}
+
+impl<'a, T: Hash + Eq + Clone + 'a> Balancer<T> for TrafficSplit<'a, T> {
+    fn add_node(&mut self, node: Node<T>) -> Result<(), DuplicatedKeyError> {
+        if self.stable.contains_id(&node.id) || self.canary.contains_id(&node.id) {
+            return Err(DuplicatedKeyError);
+        }
+        self.group_mut(&node).add_node(node)
+    }
+
+    fn remove_node(&mut self, id: &T) -> Result<(), NotFoundError> {
+        self.group_of(id).ok_or(NotFoundError)?.remove_node(id)
+    }
+
+    fn contains_id(&mut self, id: &T) -> bool {
+        self.group_of(id).is_some()
+    }
+
+    fn get_node(&self, id: &T) -> Option<&Node<T>> {
+        self.stable.get_node(id).or_else(|| self.canary.get_node(id))
+    }
+
+    /// stable nodes first.
+    fn get_nodes(&self) -> Vec<&Node<T>> {
+        let mut nodes = self.stable.get_nodes();
+        nodes.extend(self.canary.get_nodes());
+        nodes
+    }
+
+    fn set_down(&mut self, id: &T, down: bool) -> Result<(), NotFoundError> {
+        self.group_of(id).ok_or(NotFoundError)?.set_down(id, down)
+    }
+
+    fn set_overprovisioning_factor(&mut self, factor: Option<f64>) {
+        self.stable.set_overprovisioning_factor(factor);
+        self.canary.set_overprovisioning_factor(factor);
+    }
+
+    fn set_panic_threshold(&mut self, threshold: Option<f64>) {
+        self.stable.set_panic_threshold(threshold);
+        self.canary.set_panic_threshold(threshold);
+    }
+
+    fn next_id(&mut self) -> Option<&T> {
+        self.next().map(|n| &n.id)
+    }
+
+    fn next(&mut self) -> Option<&Node<T>> {
+        let id = self.select(None)?;
+        self.get_node(&id)
+    }
+
+    fn next_for(&mut self, ctx: &dyn RequestContext<T>) -> Option<&Node<T>> {
+        let id = self.select(Some(ctx))?;
+        self.get_node(&id)
+    }
+}
+
+#[cfg(test)]
+mod traffic_split_test {
+    use crate::{Balancer, BalancerEnum, Node, Request, Selector};
+    use crate::traffic_split::{fnv1a, TrafficSplit};
+
+    fn map_nodes(array: Vec<(i32, &str)>) -> Vec<Node<i32>> {
+        array.into_iter()
+            .map(|(id, version)| Node::new_with_default_weight(id).with_label("version", version))
+            .collect()
+    }
+
+    fn canary_count(balancer: &mut TrafficSplit<i32>, count: usize) -> usize {
+        (0..count)
+            .filter(|_| balancer.next().unwrap().get_label("version") == Some("v2"))
+            .count()
+    }
+
+    #[test]
+    fn split() {
+        let nodes = map_nodes(vec![(1, "v1"), (2, "v1"), (3, "v1"), (4, "v2")]);
+        let selector = Selector::parse("version=v2").unwrap();
+        let mut balancer = TrafficSplit::new(BalancerEnum::RR, BalancerEnum::WRR, selector, nodes);
+        assert_eq!(canary_count(&mut balancer, 100), 0);
+
+        balancer.set_canary_percent(10.0);
+        assert_eq!(canary_count(&mut balancer, 100), 10);
+        balancer.set_canary_percent(25.0);
+        assert_eq!(canary_count(&mut balancer, 100), 25);
+
+        // group sizes do not change the split.
+        for id in 5..10 {
+            balancer.add_node(Node::new_with_default_weight(id).with_label("version", "v2")).unwrap();
+        }
+        assert_eq!(canary_count(&mut balancer, 100), 25);
+        assert!(balancer.add_node(Node::new_with_default_weight(5)).is_err());
+
+        balancer.set_canary_percent(100.0);
+        assert_eq!(canary_count(&mut balancer, 100), 100);
+        for id in 4..10 {
+            balancer.set_down(&id, true).unwrap();
+        }
+        assert_eq!(canary_count(&mut balancer, 100), 0);
+        assert_eq!(balancer.get_nodes().len(), 9);
+        assert_eq!(balancer.get_canary().get_nodes().len(), 6);
+    }
+
+    #[test]
+    fn sticky() {
+        let nodes = map_nodes(vec![(1, "v1"), (2, "v1"), (3, "v2"), (4, "v2")]);
+        let selector = Selector::parse("version=v2").unwrap();
+        let mut balancer = TrafficSplit::new(BalancerEnum::RR, BalancerEnum::RR, selector, nodes)
+            .with_canary_percent(30.0)
+            .with_sticky(true);
+        let groups = |balancer: &mut TrafficSplit<i32>| -> Vec<bool> {
+            (0..1000)
+                .map(|user| {
+                    let request = Request::new().with_hash_key(format!("user-{}", user));
+                    balancer.next_for(&request).unwrap().get_label("version") == Some("v2")
+                })
+                .collect()
+        };
+        let before = groups(&mut balancer);
+        assert_eq!(before, groups(&mut balancer));
+        assert!((250..350).contains(&before.iter().filter(|canary| **canary).count()));
+
+        balancer.set_canary_percent(50.0);
+        let after = groups(&mut balancer);
+        assert!(before.iter().zip(&after).all(|(before, after)| !before || *after));
+        assert!((450..550).contains(&after.iter().filter(|canary| **canary).count()));
+    }
+
+    #[test]
+    fn stable_hash() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+}
@@ -1,17 +1,20 @@
 use std::hash::Hash;
 
-use crate::{Balancer, Node};
+use crate::{Balancer, Node, RequestContext};
 use crate::errors::{DuplicatedKeyError, NotFoundError};
 use crate::nodes::NodesContainer;
+use crate::selection::{self, Filter, Priorities, Select};
 
 pub struct WeightedRoundRobin<T: Hash + Eq + Clone> {
     nodes: NodesContainer<T>,
+    priorities: Priorities,
 }
 
 impl<T: Hash + Eq + Clone> WeightedRoundRobin<T> {
     pub fn new(nodes: Vec<Node<T>>) -> WeightedRoundRobin<T> {
         WeightedRoundRobin {
             nodes: NodesContainer::from(nodes),
+            priorities: Priorities::new(),
         }
     }
 }
@@ -41,51 +44,75 @@ impl<T: Hash + Eq + Clone> Balancer<T> for WeightedRoundRobin<T> {
         self.nodes.set_down(id, down)
     }
 
+    fn set_overprovisioning_factor(&mut self, factor: Option<f64>) {
+        self.priorities.set_overprovisioning_factor(factor);
+    }
+
+    fn set_panic_threshold(&mut self, threshold: Option<f64>) {
+        self.priorities.set_panic_threshold(threshold);
+    }
+
     fn next_id(&mut self) -> Option<&T> {
         self.next().map(|n| &n.id)
     }
-    
+
     fn next(&mut self) -> Option<&Node<T>> {
+        let index = selection::next(self, None)?;
+        self.nodes.get_by_index(index)
+    }
+
+    fn next_for(&mut self, ctx: &dyn RequestContext<T>) -> Option<&Node<T>> {
+        let index = selection::next(self, Some(&|node| ctx.accepts(node)))?;
+        self.nodes.get_by_index(index)
+    }
+}
+
+impl<T: Hash + Eq + Clone> WeightedRoundRobin<T> {
+    /// nodes not accepted keep their current weight, like down nodes.
+    /// ties are broken by insertion order.
+    /// index of the selected node, none if the filter accepts no node.
+    fn select(&mut self, filter: &dyn Fn(&Node<T>) -> bool) -> Option<usize> {
         let len = self.nodes.len();
-        if len == 0 {
-            return None;
-        }
         let mut total = 0;
-        let mut result = None;
-        for (_, node) in self.nodes.iter_mut() {
-            if node.is_down() {
-                continue;
-            }
+        let mut result: Option<(usize, i32)> = None;
+        for index in 0..len {
+            let node = match self.nodes.get_mut_by_index(index) {
+                Some(node) if filter(node) => node,
+                _ => continue,
+            };
             node.current_weight += node.effective_weight;
             total += node.effective_weight;
             if node.effective_weight < (node.weight as i32) {
                 node.effective_weight += 1;
             }
-            if result.is_none() {
-                result = Some(node);
-            } else {
-                result = result.map(|existed| {
-                    if existed.current_weight < node.current_weight {
-                        node
-                    } else {
-                        existed
-                    }
-                });
+            if result.is_none_or(|(_, current_weight)| current_weight < node.current_weight) {
+                result = Some((index, node.current_weight));
             }
         }
 
-        return result.map(|node| {
-            node.current_weight -= total;
-            return &*node;
-        });
+        let (index, _) = result?;
+        self.nodes.get_mut_by_index(index)?.current_weight -= total;
+        Some(index)
+    }
+}
+
+impl<T: Hash + Eq + Clone> Select<T> for WeightedRoundRobin<T> {
+    fn state(&mut self) -> (&NodesContainer<T>, &mut Priorities) {
+        (&self.nodes, &mut self.priorities)
+    }
+
+    fn select_level(&mut self, priority: usize, panic: bool, filter: Option<Filter<T>>) -> Option<usize> {
+        self.select(&|node| selection::accepts(node, priority, panic, filter))
     }
 }
 
 #[cfg(test)]
 mod weighted_round_robin_test {
     use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
 
-    use crate::{Balancer, Node};
+    use crate::{AcquireError, Balancer, ManualClock, Node, Selector};
     use crate::weighted_round_robin::WeightedRoundRobin;
 
     fn map_nodes(array: Vec<(i32, usize)>) -> Vec<Node<i32>> {
@@ -121,6 +148,21 @@ mod weighted_round_robin_test {
         }
     }
 
+    #[test]
+    fn order() {
+        // same sequence as nginx.
+        let nodes = map_nodes(vec![(1, 5), (2, 1), (3, 1)]);
+        let mut balancer = WeightedRoundRobin::new(nodes);
+        let sequence: Vec<i32> = (0..14).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![1, 1, 2, 1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1]);
+
+        let nodes: Vec<Node<i32>> = (0..20).map(Node::new_with_default_weight).collect();
+        let mut balancer = WeightedRoundRobin::new(nodes);
+        let sequence: Vec<i32> = (0..40).map(|_| *balancer.next_id().unwrap()).collect();
+        let expected: Vec<i32> = (0..40).map(|i| i % 20).collect();
+        assert_eq!(sequence, expected);
+    }
+
     #[test]
     fn add_node() {
         let mut balancer = WeightedRoundRobin::new(map_nodes(vec![
@@ -160,7 +202,7 @@ mod weighted_round_robin_test {
     #[test]
     fn down() {
         let nodes = vec![1, 2, 3];
-        let nodes = nodes.into_iter().map(|id| Node::new_with_default_weight(id)).collect();
+        let nodes = nodes.into_iter().map(Node::new_with_default_weight).collect();
         let mut balancer = WeightedRoundRobin::new(nodes);
 
         balancer.set_down(&1, true).unwrap();
@@ -178,7 +220,7 @@ mod weighted_round_robin_test {
     #[test]
     fn remove() {
         let nodes = vec![1, 2, 3];
-        let nodes = nodes.into_iter().map(|id| Node::new_with_default_weight(id)).collect();
+        let nodes = nodes.into_iter().map(Node::new_with_default_weight).collect();
         let mut balancer = WeightedRoundRobin::new(nodes);
 
         balancer.remove_node(&1).unwrap();
@@ -192,4 +234,65 @@ mod weighted_round_robin_test {
         assert!(balancer.next_id().is_none());
         assert!(balancer.next_id().is_none());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn backup() {
+        let mut balancer = WeightedRoundRobin::new(vec![
+            Node::new(1, 2),
+            Node::new(2, 5).with_backup(true),
+            Node::new(3, 1),
+            Node::new(4, 1).with_backup(true),
+        ]);
+        let sequence: Vec<i32> = (0..6).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![1, 3, 1, 1, 3, 1]);
+
+        balancer.set_down(&1, true).unwrap();
+        balancer.set_down(&3, true).unwrap();
+        let sequence: Vec<i32> = (0..6).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![2, 2, 2, 4, 2, 2]);
+
+        balancer.set_down(&3, false).unwrap();
+        assert_eq!(*balancer.next_id().unwrap(), 3);
+
+        balancer.set_down(&2, true).unwrap();
+        balancer.set_down(&3, true).unwrap();
+        balancer.set_down(&4, true).unwrap();
+        assert!(balancer.next_id().is_none());
+    }
+
+    #[test]
+    fn rate_limit() {
+        let clock = Arc::new(ManualClock::new());
+        let mut balancer = WeightedRoundRobin::new(vec![
+            Node::new(1, 3).with_rate_limit_clock(1.0, 2, clock.clone()),
+            Node::new(2, 1).with_rate_limit_clock(1.0, 2, clock.clone()),
+        ]);
+        let sequence: Vec<i32> = (0..4).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![1, 1, 2, 2]);
+        assert!(balancer.get_node(&1).unwrap().is_rate_limited());
+        assert!(balancer.next_id().is_none());
+        assert_eq!(balancer.acquire().err(), Some(AcquireError::RateLimited));
+
+        clock.advance(Duration::from_secs(1));
+        let sequence: Vec<i32> = (0..2).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![2, 1]);
+        assert!(balancer.next_id().is_none());
+    }
+
+    #[test]
+    fn next_matching() {
+        let mut balancer = WeightedRoundRobin::new(vec![
+            Node::new(1, 5).with_label("version", "v1"),
+            Node::new(2, 1).with_label("version", "v1"),
+            Node::new(3, 1).with_label("version", "v2"),
+        ]);
+        let v2 = Selector::parse("version=v2").unwrap();
+        let v1 = Selector::parse("version!=v2").unwrap();
+        assert_eq!(*balancer.next_matching(&v2).unwrap().get_id(), 3);
+        assert_eq!(*balancer.next_matching(&v2).unwrap().get_id(), 3);
+        // the v1 nodes keep the sequence of a balancer without node 3.
+        let sequence: Vec<i32> = (0..6).map(|_| *balancer.next_matching(&v1).unwrap().get_id()).collect();
+        assert_eq!(sequence, vec![1, 1, 1, 2, 1, 1]);
+        assert!(balancer.next_matching(&Selector::parse("version=v3").unwrap()).is_none());
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::BTreeMap;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use crate::errors::{DuplicatedKeyError, NotFoundError};
+use crate::selection::{self, FULL_LOAD};
+use crate::{Balancer, Node, RequestContext};
+
+/// Locality aware routing over another balancer, like Envoy zone aware routing.
+///
+/// The capacity of a zone is the sum of weights of its live nodes.
+/// When the local zone has at least its share of the callers (see `with_caller_shares`) as
+/// share of the capacity, all traffic stays local. Otherwise local nodes get as much as they can
+/// take and the rest goes to the zones with more capacity than callers, in proportion to the
+/// capacity they have left. Zones are split with smooth weighted round robin, nodes are selected
+/// by the inner balancer. Nodes without zone are in the zone "".
+///
+/// Only the first priority level with a live node is split by zone (Envoy also only routes
+/// the first priority by zone): lower levels do not count as capacity and are not selected
+/// for a zone while that level has a live node.
+pub struct ZoneAware<T: Hash + Eq + Clone, B: Balancer<T>> {
+    inner: B,
+    local_zone: String,
+    /// first priority level with a live node, updated when nodes change.
+    priority: Option<usize>,
+    /// share of the callers in each zone, normalized. empty is unknown.
+    caller_shares: BTreeMap<String, f64>,
+    /// share of the traffic of each zone in basis points, updated when nodes change.
+    loads: Vec<(String, i64)>,
+    current_weights: BTreeMap<String, i64>,
+    phantom: PhantomData<T>,
+}
+
+impl<T: Hash + Eq + Clone, B: Balancer<T>> ZoneAware<T, B> {
+    /// without caller shares, all traffic stays in the local zone while it has live nodes.
+    pub fn new(inner: B, local_zone: impl Into<String>) -> ZoneAware<T, B> {
+        let mut balancer = ZoneAware {
+            inner,
+            local_zone: local_zone.into(),
+            priority: None,
+            caller_shares: BTreeMap::new(),
+            loads: Vec::new(),
+            current_weights: BTreeMap::new(),
+            phantom: PhantomData,
+        };
+        balancer.update_loads();
+        balancer
+    }
+
+    /// number (or share) of callers in each zone, e.g. the number of instances of the caller.
+    pub fn with_caller_shares(mut self, shares: Vec<(String, f64)>) -> ZoneAware<T, B> {
+        let total: f64 = shares.iter().map(|(_, share)| share).sum();
+        self.caller_shares = shares
+            .into_iter()
+            .filter(|_| total > 0.0)
+            .map(|(zone, share)| (zone, share / total))
+            .collect();
+        self.update_loads();
+        self
+    }
+
+    pub fn get_inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// share of the traffic of each zone in basis points, empty without live node.
+    pub fn loads(&self) -> &[(String, i64)] {
+        &self.loads
+    }
+
+    /// O(n)
+    fn update_loads(&mut self) {
+        let nodes = self.inner.get_nodes();
+        self.priority = nodes.iter().filter(|node| !node.is_down()).map(|node| node.priority).min();
+        let mut capacity: BTreeMap<String, f64> = BTreeMap::new();
+        for node in nodes {
+            if !node.is_down() && Some(node.priority) == self.priority {
+                *capacity.entry(node.get_zone().unwrap_or("").to_string()).or_insert(0.0) +=
+                    node.weight as f64;
+            }
+        }
+        let total: f64 = capacity.values().sum();
+        let local_capacity = capacity.get(&self.local_zone).copied().unwrap_or(0.0) / total;
+        let local_callers = self.caller_shares.get(&self.local_zone).copied().unwrap_or(0.0);
+        self.loads = if total <= 0.0 {
+            Vec::new()
+        } else if local_capacity > 0.0
+            && (self.caller_shares.is_empty() || local_capacity >= local_callers)
+        {
+            vec![(self.local_zone.clone(), FULL_LOAD)]
+        } else {
+            // the local zone takes what it can, the rest spills to zones with capacity left.
+            let local = if local_callers > 0.0 { local_capacity / local_callers } else { 0.0 };
+            let residual: Vec<(String, f64)> = capacity
+                .iter()
+                .filter(|(zone, _)| **zone != self.local_zone)
+                .map(|(zone, capacity)| {
+                    let callers = self.caller_shares.get(zone).copied().unwrap_or(0.0);
+                    (zone.clone(), capacity / total - callers)
+                })
+                .filter(|(_, residual)| *residual > 0.0)
+                .collect();
+            let residual_total: f64 = residual.iter().map(|(_, residual)| residual).sum();
+            let local_load = (local * FULL_LOAD as f64).round() as i64;
+            let remaining = (FULL_LOAD - local_load) as f64;
+            let mut loads = vec![(self.local_zone.clone(), local_load)];
+            if residual_total > 0.0 {
+                loads.extend(residual.into_iter().map(|(zone, residual)| {
+                    (zone, (remaining * residual / residual_total).round() as i64)
+                }));
+            } else {
+                // callers unknown in every other zone, spill by capacity.
+                let others: f64 = capacity
+                    .iter()
+                    .filter(|(zone, _)| **zone != self.local_zone)
+                    .map(|(_, capacity)| capacity)
+                    .sum();
+                loads.extend(
+                    capacity
+                        .iter()
+                        .filter(|(zone, _)| **zone != self.local_zone)
+                        .map(|(zone, capacity)| {
+                            (zone.clone(), (remaining * capacity / others).round() as i64)
+                        }),
+                );
+            }
+            loads.retain(|(_, load)| *load > 0);
+            loads
+        };
+    }
+
+    /// smooth weighted round robin between zones. O(number of zones)
+    fn choose_zone(&mut self) -> Option<String> {
+        selection::smooth_choose(&mut self.current_weights, &self.loads)
+    }
+
+    /// a node of the chosen zone if there is one, else any node.
+    fn select(&mut self, ctx: Option<&dyn RequestContext<T>>) -> Option<T> {
+        if let Some(zone) = self.choose_zone() {
+            let in_zone = InZone { zone: &zone, priority: self.priority, ctx };
+            if let Some(node) = self.inner.next_for(&in_zone) {
+                return Some(node.id.clone());
+            }
+        }
+        let node = match ctx {
+            Some(ctx) => self.inner.next_for(ctx),
+            None => self.inner.next(),
+        };
+        node.map(|node| node.id.clone())
+    }
+}
+
+struct InZone<'a, T: Hash + Eq + Clone> {
+    zone: &'a str,
+    /// of the nodes split by zone, the zone does not move traffic to lower levels.
+    priority: Option<usize>,
+    ctx: Option<&'a dyn RequestContext<T>>,
+}
+
+impl<T: Hash + Eq + Clone> RequestContext<T> for InZone<'_, T> {
+    fn accepts(&self, node: &Node<T>) -> bool {
+        node.get_zone().unwrap_or("") == self.zone
+            && Some(node.priority) == self.priority
+            && self.ctx.is_none_or(|ctx| ctx.accepts(node))
+    }
+}
+
+impl<T: Hash + Eq + Clone, B: Balancer<T>> Balancer<T> for ZoneAware<T, B> {
+    fn add_node(&mut self, node: Node<T>) -> Result<(), DuplicatedKeyError> {
+        self.inner.add_node(node)?;
+        self.update_loads();
+        Ok(())
+    }
+
+    fn remove_node(&mut self, id: &T) -> Result<(), NotFoundError> {
+        self.inner.remove_node(id)?;
+        self.update_loads();
+        Ok(())
+    }
+
+    fn contains_id(&mut self, id: &T) -> bool {
+        self.inner.contains_id(id)
+    }
+
+    fn get_node(&self, id: &T) -> Option<&Node<T>> {
+        self.inner.get_node(id)
+    }
+
+    fn get_nodes(&self) -> Vec<&Node<T>> {
+        self.inner.get_nodes()
+    }
+
+    fn set_down(&mut self, id: &T, down: bool) -> Result<(), NotFoundError> {
+        self.inner.set_down(id, down)?;
+        self.update_loads();
+        Ok(())
+    }
+
+    fn set_overprovisioning_factor(&mut self, factor: Option<f64>) {
+        self.inner.set_overprovisioning_factor(factor);
+    }
+
+    fn set_panic_threshold(&mut self, threshold: Option<f64>) {
+        self.inner.set_panic_threshold(threshold);
+    }
+
+    fn next_id(&mut self) -> Option<&T> {
+        self.next().map(|n| &n.id)
+    }
+
+    fn next(&mut self) -> Option<&Node<T>> {
+        let id = self.select(None)?;
+        self.inner.get_node(&id)
+    }
+
+    fn next_for(&mut self, ctx: &dyn RequestContext<T>) -> Option<&Node<T>> {
+        let id = self.select(Some(ctx))?;
+        self.inner.get_node(&id)
+    }
+}
+
+#[cfg(test)]
+mod zone_aware_test {
+    use std::collections::HashMap;
+
+    use crate::{Balancer, Node};
+    use crate::round_robin::RoundRobin;
+    use crate::weighted_round_robin::WeightedRoundRobin;
+    use crate::zone_aware::ZoneAware;
+
+    fn map_nodes(array: Vec<(i32, usize, &str)>) -> Vec<Node<i32>> {
+        array.into_iter()
+            .map(|(id, weight, zone)| Node::new(id, weight).with_zone(zone))
+            .collect()
+    }
+
+    fn zone_counts<B: Balancer<i32>>(balancer: &mut ZoneAware<i32, B>, count: usize) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for _ in 0..count {
+            let zone = balancer.next().unwrap().get_zone().unwrap().to_string();
+            *counts.entry(zone).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn local() {
+        let nodes = map_nodes(vec![(1, 1, "a"), (2, 1, "a"), (3, 1, "b"), (4, 1, "c")]);
+        let mut balancer = ZoneAware::new(RoundRobin::new(nodes), "a");
+        let sequence: Vec<i32> = (0..4).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![1, 2, 1, 2]);
+
+        balancer.set_down(&1, true).unwrap();
+        balancer.set_down(&2, true).unwrap();
+        assert_eq!(balancer.loads(), &[("b".to_string(), 5000), ("c".to_string(), 5000)]);
+        let sequence: Vec<i32> = (0..4).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![3, 4, 3, 4]);
+    }
+
+    #[test]
+    fn spill() {
+        // the local zone has 20% of capacity for 40% of callers: half of the traffic stays local.
+        let nodes = map_nodes(vec![(1, 2, "a"), (2, 4, "b"), (3, 4, "c")]);
+        let shares = vec![("a".to_string(), 4.0), ("b".to_string(), 4.0), ("c".to_string(), 2.0)];
+        let mut balancer = ZoneAware::new(WeightedRoundRobin::new(nodes), "a").with_caller_shares(shares);
+        assert_eq!(balancer.loads(), &[("a".to_string(), 5000), ("c".to_string(), 5000)]);
+        let counts = zone_counts(&mut balancer, 100);
+        assert_eq!((counts["a"], counts["c"]), (50, 50));
+
+        // enough local capacity.
+        balancer.add_node(Node::new(4, 4).with_zone("a")).unwrap();
+        assert_eq!(balancer.loads(), &[("a".to_string(), 10000)]);
+        assert_eq!(zone_counts(&mut balancer, 100)["a"], 100);
+
+        balancer.remove_node(&1).unwrap();
+        balancer.remove_node(&4).unwrap();
+        assert_eq!(balancer.loads(), &[("b".to_string(), 2500), ("c".to_string(), 7500)]);
+
+        let mut empty: ZoneAware<i32, _> = ZoneAware::new(RoundRobin::new(vec![]), "a");
+        assert!(empty.loads().is_empty());
+        assert!(empty.next().is_none());
+    }
+
+    #[test]
+    fn backup() {
+        // the local zone only has a backup node.
+        let nodes = vec![
+            Node::new(1, 1).with_zone("a").with_backup(true),
+            Node::new(2, 1).with_zone("b"),
+            Node::new(3, 1).with_zone("c"),
+        ];
+        let mut balancer = ZoneAware::new(RoundRobin::new(nodes), "a");
+        assert_eq!(balancer.loads(), &[("b".to_string(), 5000), ("c".to_string(), 5000)]);
+        let sequence: Vec<i32> = (0..4).map(|_| *balancer.next_id().unwrap()).collect();
+        assert_eq!(sequence, vec![2, 3, 2, 3]);
+
+        balancer.set_down(&2, true).unwrap();
+        balancer.set_down(&3, true).unwrap();
+        assert_eq!(balancer.loads(), &[("a".to_string(), 10000)]);
+        assert_eq!(*balancer.next_id().unwrap(), 1);
+    }
+
+    #[test]
+    fn boxed() {
+        let nodes = map_nodes(vec![(1, 1, "a"), (2, 1, "b")]);
+        let mut balancer = ZoneAware::new(crate::new(crate::BalancerEnum::Random, nodes), "b");
+        for _ in 0..10 {
+            assert_eq!(*balancer.next_id().unwrap(), 2);
+        }
+    }
+}